@@ -0,0 +1,35 @@
+// Compare the memory backends on a loop which fetches, writes and jumps.
+// cargo bench --bench memory
+use std::time::Instant;
+use brandon::bvm::VM;
+use brandon::bvm::instructions::Opcode;
+use brandon::bvm::memory::{Backend, Memory, Sparse, Paged};
+
+fn main() {
+    let program = [
+        Opcode::MOV_REG_IMM as u8, 0b0001_0000, 1, 0, 0, 0, 0, 0, // MOV R01 0x00
+        Opcode::ADD as u8, 0b01_000001, 1, 1, 1, // ADD R01 R01 0x01
+        Opcode::MOV_MEM_REG as u8, 0, 0, 0x10, 0, 1, // MOV [0x1000] R01
+        Opcode::CMP_LT_REG_IMM as u8, 0b0011_0000, 1, 0x0F, 0x42, 0x40, // CMPLT R01 1000000
+        Opcode::JMP_IMM as u8, 1, 8, // JMP [0x08]
+        Opcode::CAL as u8, 0x9D // CAL HLT
+    ];
+
+    let backends: Vec<(&str, Box<dyn Backend>)> = vec![
+        ("sparse", Box::new(Sparse::default())),
+        ("paged", Box::new(Paged::default()))
+    ];
+
+    for (name, backend) in backends {
+        let mut vm = VM::new();
+
+        vm.mem = Memory::with_backend(backend);
+        vm.mem.write_bytes(0, &program);
+
+        let start = Instant::now();
+        vm.run().unwrap();
+
+        assert_eq!(vm.mem.read(0x1000), Some(1_000_000));
+        println!("{:<8} {:?}", name, start.elapsed());
+    }
+}
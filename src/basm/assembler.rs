@@ -1,26 +1,90 @@
 #![allow(dead_code)]
+use std::fmt;
+use std::collections::HashMap;
 use super::tokenizer::{Token, TokenType};
+use crate::bvm::instructions::Opcode;
+use crate::bvm::externals::u8arr_to_i64;
+use crate::bvm::container::{Container, Section, SectionKind, Symbol};
+use crate::bvm::{VM, CALLS};
+
+// Output is one image from address 0, so #LFH cannot continue past this
+// word address (8 MiB)
+const MAX_LFH: u32 = 0x10_0000;
 
 pub struct Assembler<'a> {
     tokens: &'a [Token],
     index: usize,
-    addr: u32
+    // Word address of the current label or directive
+    addr: u32,
+    // Label addresses from the previous pass, used to resolve references
+    labels: HashMap<String, u32>,
+    // Labels defined so far in the current pass
+    defined: HashMap<String, u32>,
+    // First reference to a label missing from the previous pass
+    undefined: Option<AsmError>,
+    // Names usable with CAL and their call numbers
+    calls: Vec<(String, u8)>,
+    // Word addresses where instructions or directive output start,
+    // which become code and data sections
+    runs: Vec<(u32, SectionKind)>
+}
+
+#[derive(Debug, PartialEq)]
+pub struct AsmError {
+    pub line: usize,
+    pub message: String
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+enum Operand {
+    Register(u8),
+    Address(u32),
+    Immediate(u64),
+    Float(f64)
 }
 
 impl<'a> Assembler<'a> {
     pub fn load(tokens: &'a [Token]) -> Assembler<'a> {
         Assembler {
-            tokens: tokens,
+            tokens,
             index: 0,
-            addr: 0
+            addr: 0,
+            labels: HashMap::new(),
+            defined: HashMap::new(),
+            undefined: None,
+            calls: CALLS.iter().map(|(name, code)| (name.to_string(), *code)).collect(),
+            runs: Vec::new()
         }
     }
 
-    fn cur(&self) -> &Token {
+    pub fn for_vm(tokens: &'a [Token], vm: &VM) -> Assembler<'a> {
+        // Assembler accepting the CAL names of the built in calls and
+        // the host functions registered with vm
+        let mut assembler = Assembler::load(tokens);
+        assembler.use_calls(vm.calls());
+        assembler
+    }
+
+    pub fn use_calls(&mut self, calls: Vec<(String, u8)>) {
+        // Replace the CAL names, usually with VM::calls
+        self.calls = calls;
+    }
+
+    fn call(&self, name: &str) -> Option<u8> {
+        let name = name.to_lowercase();
+        self.calls.iter().find(|(n, _)| *n == name).map(|(_, code)| *code)
+    }
+
+    fn cur(&self) -> &'a Token {
         &self.tokens[self.index]
     }
 
-    fn peak(&self) -> Option<&Token> {
+    fn peak(&self) -> Option<&'a Token> {
         if self.index + 1 < self.tokens.len() {
             Some(&self.tokens[self.index + 1])
         } else {
@@ -28,48 +92,1182 @@ impl<'a> Assembler<'a> {
         }
     }
 
-    fn next(&mut self) -> Option<&Token> {
+    fn next(&mut self) -> Option<&'a Token> {
         if self.index + 1 < self.tokens.len() {
             self.index += 1;
-            Some(&self.cur())
+            Some(self.cur())
         } else {
             None
         }
     }
 
-    pub fn assemble(&mut self) -> Vec<u8> {
-        let mut buf: Vec<u8> = Vec::with_capacity(self.tokens.len());
-        let mut passes = 0;
+    pub fn assemble(&mut self) -> Result<Vec<u8>, AsmError> {
+        // Assemble tokens into bytecode which is loaded at address 0.
+        // Label references take the addresses found by the previous
+        // pass, starting from 0, and passes repeat until no label moves.
+        // Addresses only grow from pass to pass, so this terminates.
+        loop {
+            let buf = self.pass()?;
+
+            if self.defined == self.labels {
+                return match self.undefined.take() {
+                    Some(err) => Err(err),
+                    None => Ok(buf)
+                };
+            }
+
+            self.labels = std::mem::take(&mut self.defined);
+        }
+    }
+
+    pub fn container(&mut self) -> Result<Container, AsmError> {
+        // Assemble into a container. Instructions are code and the output
+        // of directives is data, so programs can write to their #LFH and
+        // #STR buffers but not to their code. Execution starts at the
+        // first instruction, and labels become the symbol table.
+        let bytes = self.assemble()?;
+        let mut container = Container::new(0);
+
+        for (i, (load, kind)) in self.runs.iter().enumerate() {
+            let start = *load as usize * 8;
+            let end = self.runs.get(i + 1).map_or(bytes.len(), |(next, _)| *next as usize * 8);
+
+            if start == end {
+                continue;
+            }
+
+            // Runs split only by an empty #LFH join back together
+            if let Some(last) = container.sections.last_mut() {
+                if last.kind == *kind && (last.load as usize + last.words() as usize) * 8 == start {
+                    last.bytes.extend_from_slice(&bytes[start..end]);
+                    continue;
+                }
+            }
+
+            if *kind == SectionKind::Code && container.sections.iter().all(|section| section.kind != SectionKind::Code) {
+                container.entry = *load;
+            }
+
+            container.sections.push(Section {
+                kind: *kind,
+                load: *load,
+                bytes: bytes[start..end].to_vec()
+            });
+        }
+
+        for (name, addr) in self.labels() {
+            container.symbols.push(Symbol {
+                name: name.to_owned(),
+                addr
+            });
+        }
+
+        Ok(container)
+    }
+
+    pub fn labels(&self) -> Vec<(&str, u32)> {
+        // Labels and their word addresses, ordered by address
+        let mut labels: Vec<(&str, u32)> = self.labels.iter()
+            .map(|(name, addr)| (name.as_str(), *addr))
+            .collect();
+
+        labels.sort_by_key(|(name, addr)| (*addr, *name));
+        labels
+    }
+
+    fn pass(&mut self) -> Result<Vec<u8>, AsmError> {
+        // Instructions are packed back to back. Labels, directives and
+        // the first instruction after a directive start on a word
+        // boundary, so labels can name both code and data.
+        let mut buf: Vec<u8> = Vec::with_capacity(self.tokens.len() * 8);
+
+        self.index = 0;
+        self.addr = 0;
+        self.defined.clear();
+        self.undefined = None;
+        self.runs.clear();
 
         while self.index < self.tokens.len() {
-            match self.cur().r#type {
-                TokenType::DIRECTIVE => {},
-                TokenType::STRING => {},
-                TokenType::NUMBER => {},
-                TokenType::ADDRESS => {},
-                TokenType::REGISTER => {},
-                TokenType::WORD => {}
+            let token = self.cur();
+
+            match token.r#type {
+                TokenType::DIRECTIVE => {
+                    self.addr = align(&mut buf);
+                    self.run(&mut buf, SectionKind::Data);
+                    self.directive(&mut buf)?;
+                },
+                TokenType::WORD if self.is_label() => {
+                    self.addr = align(&mut buf);
+                    self.label()?;
+                },
+                TokenType::WORD => {
+                    let inst = self.instruction()?;
+
+                    self.run(&mut buf, SectionKind::Code);
+                    buf.extend_from_slice(&inst);
+                },
+                _ => return Err(error(token, &format!("unexpected operand {}", token.val)))
+            }
+
+            self.index += 1;
+        }
+
+        align(&mut buf);
+        Ok(buf)
+    }
+
+    fn run(&mut self, buf: &mut Vec<u8>, kind: SectionKind) {
+        // Start a new section at the next word when switching between
+        // instructions and directives, so no word holds both
+        if self.runs.last().map(|(_, last)| *last) != Some(kind) {
+            self.runs.push((align(buf), kind));
+        }
+    }
+
+    fn is_label(&self) -> bool {
+        // Words which are not instructions define a label when followed
+        // by another statement or the end of the source
+        let follows_statement = match self.peak() {
+            Some(next) => next.r#type == TokenType::WORD || next.r#type == TokenType::DIRECTIVE,
+            None => true
+        };
+
+        !is_valid_instruction(&self.cur().val) && follows_statement
+    }
+
+    fn label(&mut self) -> Result<(), AsmError> {
+        // Define a label at the current address
+        let token = self.cur();
+
+        if !is_valid_label(&token.val) {
+            return Err(error(token, &format!("invalid label name {}", token.val)));
+        }
+
+        if self.defined.insert(token.val.clone(), self.addr).is_some() {
+            return Err(error(token, &format!("label {} is already defined", token.val)));
+        }
+
+        Ok(())
+    }
+
+    fn resolve(&mut self, token: &Token, name: &str) -> u32 {
+        // Address of a label as of the previous pass. Unknown labels are
+        // 0 until the final pass shows they are never defined.
+        match self.labels.get(name) {
+            Some(addr) => *addr,
+            None => {
+                if self.undefined.is_none() {
+                    self.undefined = Some(error(token, &format!("undefined label {}", name)));
+                }
+
+                0
             }
         }
+    }
+
+    fn operand(&mut self) -> Result<&'a Token, AsmError> {
+        // Advance to the next operand of the current instruction
+        let token = self.cur();
+
+        match self.next() {
+            Some(next) => Ok(next),
+            None => Err(error(token, "missing operand"))
+        }
+    }
+
+    fn parse_operand(&mut self) -> Result<Operand, AsmError> {
+        let token = self.operand()?;
+
+        match token.r#type {
+            TokenType::REGISTER => {
+                let num = parse_number(token, &token.val)?;
+
+                if num > 0xFF {
+                    return Err(error(token, &format!("register R{} does not exist", token.val)));
+                }
+
+                Ok(Operand::Register(num as u8))
+            },
+            TokenType::ADDRESS if is_valid_label(&token.val[1..]) => {
+                Ok(Operand::Address(self.resolve(token, &token.val[1..])))
+            },
+            TokenType::ADDRESS => {
+                let num = parse_number(token, &token.val[1..])?;
+
+                if num > u32::MAX as u64 {
+                    return Err(error(token, &format!("address {}] is out of range", token.val)));
+                }
+
+                Ok(Operand::Address(num as u32))
+            },
+            TokenType::NUMBER if is_float(&token.val) => match token.val.parse() {
+                Ok(num) => Ok(Operand::Float(num)),
+                Err(_) => Err(error(token, &format!("invalid float {}", token.val)))
+            },
+            TokenType::NUMBER => Ok(Operand::Immediate(parse_number(token, &token.val)?)),
+            _ => Err(error(token, &format!("unexpected operand {}", token.val)))
+        }
+    }
+
+    fn parse_target(&mut self) -> Result<Operand, AsmError> {
+        // Parse a jump target. Labels hold word addresses while jumps
+        // take byte addresses.
+        let operand = self.parse_operand()?;
+        let token = self.cur();
+
+        match operand {
+            Operand::Address(addr) if is_valid_label(&token.val[1..]) => match addr.checked_mul(8) {
+                Some(addr) => Ok(Operand::Address(addr)),
+                None => Err(error(token, &format!("label {} is out of jump range", &token.val[1..])))
+            },
+            _ => Ok(operand)
+        }
+    }
+
+    fn parse_typed(&mut self, opcode: Opcode) -> Result<Operand, AsmError> {
+        // Parse an operand of opcode, turning immediates into f64 bit
+        // patterns for float opcodes
+        let operand = self.parse_operand()?;
+
+        match operand {
+            Operand::Immediate(num) if opcode.is_float() => Ok(Operand::Immediate((num as i64 as f64).to_bits())),
+            Operand::Float(num) if opcode.is_float() => Ok(Operand::Immediate(num.to_bits())),
+            Operand::Float(_) => {
+                let token = self.cur();
+                Err(error(token, &format!("float {} is only allowed in float instructions", token.val)))
+            },
+            _ => Ok(operand)
+        }
+    }
+
+    fn instruction(&mut self) -> Result<Vec<u8>, AsmError> {
+        // Encode the instruction at the current token and its operands
+        let token = self.cur();
+        let mnemonic = token.val.to_lowercase();
+
+        if !is_valid_instruction(&mnemonic) {
+            return Err(error(token, &format!("unknown instruction {}", token.val)));
+        }
+
+        let mut inst: Vec<u8> = Vec::with_capacity(16);
+
+        match mnemonic.as_str() {
+            "mov" => {
+                let dst = self.parse_operand()?;
+                let src = match self.parse_operand()? {
+                    // Floats are moved as their bit pattern
+                    Operand::Float(num) => Operand::Immediate(num.to_bits()),
+                    src => src
+                };
+
+                match (dst, src) {
+                    (Operand::Register(dst), Operand::Register(src)) => {
+                        inst.extend_from_slice(&[Opcode::MOV_REG_REG as u8, dst, src]);
+                    },
+                    (Operand::Register(dst), Operand::Address(src)) => {
+                        inst.extend_from_slice(&[Opcode::MOV_REG_MEM as u8, dst]);
+                        inst.extend_from_slice(&src.to_be_bytes());
+                    },
+                    (Operand::Address(dst), Operand::Register(src)) => {
+                        inst.push(Opcode::MOV_MEM_REG as u8);
+                        inst.extend_from_slice(&dst.to_be_bytes());
+                        inst.push(src);
+                    },
+                    (Operand::Address(dst), Operand::Address(src)) => {
+                        let dst = addr_bytes(dst);
+                        let src = addr_bytes(src);
+
+                        inst.push(Opcode::MOV_MEM_MEM as u8);
+                        inst.push((dst.len() << 4 | src.len()) as u8);
+                        inst.extend_from_slice(&dst);
+                        inst.extend_from_slice(&src);
+                    },
+                    (Operand::Register(dst), Operand::Immediate(src)) => {
+                        let src = imm_bytes(src);
+
+                        inst.push(Opcode::MOV_REG_IMM as u8);
+                        inst.push((src.len() << 4) as u8);
+                        inst.push(dst);
+                        inst.extend_from_slice(&src);
+                    },
+                    (Operand::Address(dst), Operand::Immediate(src)) => {
+                        let dst = addr_bytes(dst);
+                        let src = imm_bytes(src);
+
+                        inst.push(Opcode::MOV_MEM_IMM as u8);
+                        inst.push((dst.len() << 4 | src.len()) as u8);
+                        inst.extend_from_slice(&dst);
+                        inst.extend_from_slice(&src);
+                    },
+                    _ => return Err(error(token, "cannot mov into an immediate"))
+                }
+            },
+            "swp" => {
+                // Registers are one byte wide, so addresses are always
+                // written with at least two bytes
+                let mut operands: Vec<Vec<u8>> = Vec::with_capacity(2);
+
+                for _ in 0..2 {
+                    match self.parse_operand()? {
+                        Operand::Register(reg) => operands.push(vec![reg]),
+                        Operand::Address(addr) => {
+                            let bytes = addr.to_be_bytes();
+                            let skip = usize::min(addr.leading_zeros() as usize / 8, 2);
+
+                            operands.push(bytes[skip..].to_vec());
+                        },
+                        _ => return Err(error(token, "swp expects registers or addresses"))
+                    }
+                }
+
+                inst.push(Opcode::SWP as u8);
+                inst.push((operands[0].len() << 4 | operands[1].len()) as u8);
+                inst.extend_from_slice(&operands[0]);
+                inst.extend_from_slice(&operands[1]);
+            },
+            "jmp" | "jsr" | "call" => {
+                let target = self.parse_target()?;
+
+                match target {
+                    Operand::Register(reg) if mnemonic == "jmp" => {
+                        inst.extend_from_slice(&[Opcode::JMP_REG as u8, reg]);
+                    },
+                    Operand::Address(addr) => {
+                        let opcode = match mnemonic.as_str() {
+                            "jmp" => Opcode::JMP_IMM,
+                            "jsr" => Opcode::JSR,
+                            _ => Opcode::CALL
+                        };
+                        let addr = addr_bytes(addr);
+
+                        inst.push(opcode as u8);
+                        inst.push(addr.len() as u8);
+                        inst.extend_from_slice(&addr);
+                    },
+                    _ => return Err(error(token, &format!("{} expects an address", mnemonic)))
+                }
+            },
+            "jz" | "jnz" | "jlt" | "jge" | "jc" | "jnc" => {
+                let opcode = match mnemonic.as_str() {
+                    "jz" => Opcode::JZ,
+                    "jnz" => Opcode::JNZ,
+                    "jlt" => Opcode::JLT,
+                    "jge" => Opcode::JGE,
+                    "jc" => Opcode::JC,
+                    _ => Opcode::JNC
+                };
+
+                match self.parse_target()? {
+                    Operand::Address(addr) => {
+                        let addr = addr_bytes(addr);
+
+                        inst.extend_from_slice(&[opcode as u8, addr.len() as u8]);
+                        inst.extend_from_slice(&addr);
+                    },
+                    // Numbers are displacements in bytes from the end of
+                    // the jump
+                    Operand::Immediate(disp) => {
+                        if (disp as i64) < i32::MIN as i64 || (disp as i64) > i32::MAX as i64 {
+                            return Err(error(token, &format!("{} displacement is out of range", mnemonic)));
+                        }
+
+                        let disp = simm_bytes(disp);
+
+                        inst.extend_from_slice(&[opcode as u8, 0x80 | disp.len() as u8]);
+                        inst.extend_from_slice(&disp);
+                    },
+                    _ => return Err(error(token, &format!("{} expects an address or displacement", mnemonic)))
+                }
+            },
+            "ret" => {
+                // Pops the return address pushed by CALL. JSR leaves it
+                // in R255 instead, return from those with JMP R255.
+                inst.push(Opcode::RET as u8);
+            },
+            "push" => {
+                match self.parse_operand()? {
+                    Operand::Register(reg) => {
+                        inst.extend_from_slice(&[Opcode::PUSH as u8, 0b00 << 6, reg]);
+                    },
+                    Operand::Immediate(num) => {
+                        let num = imm_bytes(num);
+
+                        inst.extend_from_slice(&[Opcode::PUSH as u8, 0b01 << 6 | num.len() as u8]);
+                        inst.extend_from_slice(&num);
+                    },
+                    _ => return Err(error(token, "push expects a register or immediate"))
+                }
+            },
+            "pop" => {
+                match self.parse_operand()? {
+                    Operand::Register(reg) => inst.extend_from_slice(&[Opcode::POP as u8, reg]),
+                    _ => return Err(error(token, "pop expects a register"))
+                }
+            },
+            "cmpeq" | "cmple" | "cmpge" | "cmplt" | "cmpgt" |
+            "cmpile" | "cmpige" | "cmpilt" | "cmpigt" |
+            "cmpfeq" | "cmpfle" | "cmpfge" | "cmpflt" | "cmpfgt" => {
+                let (reg_reg, reg_imm) = comparison(&mnemonic[3..]);
+                let lhs = self.parse_operand()?;
+                let rhs = self.parse_typed(reg_imm)?;
+
+                match (lhs, rhs) {
+                    (Operand::Register(lhs), Operand::Register(rhs)) => {
+                        inst.extend_from_slice(&[reg_reg as u8, lhs, rhs]);
+                    },
+                    (Operand::Register(lhs), Operand::Immediate(rhs)) => {
+                        let rhs = width(reg_imm)(rhs);
+
+                        inst.push(reg_imm as u8);
+                        inst.push((rhs.len() << 4) as u8);
+                        inst.push(lhs);
+                        inst.extend_from_slice(&rhs);
+                    },
+                    _ => return Err(error(token, &format!("{} expects a register first", mnemonic)))
+                }
+            },
+            "cmpeqz" | "cmplez" | "cmpgez" | "cmpltz" | "cmpgtz" |
+            "cmpilez" | "cmpigez" | "cmpiltz" | "cmpigtz" |
+            "cmpfeqz" | "cmpflez" | "cmpfgez" | "cmpfltz" | "cmpfgtz" => {
+                // Compare against zero, encoded as an empty immediate
+                let (_, reg_imm) = comparison(mnemonic[3..].trim_end_matches('z'));
+
+                match self.parse_operand()? {
+                    Operand::Register(reg) => inst.extend_from_slice(&[reg_imm as u8, 0, reg]),
+                    _ => return Err(error(token, &format!("{} expects a register", mnemonic)))
+                }
+            },
+            "and" | "or" | "xor" | "shl" | "shr" | "sar" | "rol" | "ror" |
+            "add" | "sub" | "mul" | "div" | "rem" | "iadd" | "isub" | "imul" | "idiv" |
+            "irem" | "fadd" | "fsub" | "fmul" | "fdiv" => {
+                let opcode = match mnemonic.as_str() {
+                    "and" => Opcode::AND,
+                    "or" => Opcode::OR,
+                    "xor" => Opcode::XOR,
+                    "shl" => Opcode::SHL,
+                    "shr" => Opcode::SHR,
+                    "sar" => Opcode::SAR,
+                    "rol" => Opcode::ROL,
+                    "ror" => Opcode::ROR,
+                    "add" => Opcode::ADD,
+                    "sub" => Opcode::SUB,
+                    "mul" => Opcode::MUL,
+                    "div" => Opcode::DIV,
+                    "rem" => Opcode::REM,
+                    "iadd" => Opcode::IADD,
+                    "isub" => Opcode::ISUB,
+                    "imul" => Opcode::IMUL,
+                    "idiv" => Opcode::IDIV,
+                    "irem" => Opcode::IREM,
+                    "fadd" => Opcode::FADD,
+                    "fsub" => Opcode::FSUB,
+                    "fmul" => Opcode::FMUL,
+                    _ => Opcode::FDIV
+                };
+
+                let dst = match self.parse_operand()? {
+                    Operand::Register(dst) => dst,
+                    _ => return Err(error(token, &format!("{} expects a destination register", mnemonic)))
+                };
+                let src1 = self.parse_typed(opcode)?;
+                let src2 = self.parse_typed(opcode)?;
+
+                inst.push(opcode as u8);
+
+                match (src1, src2) {
+                    (Operand::Register(src1), Operand::Register(src2)) => {
+                        inst.extend_from_slice(&[0b00 << 6, dst, src1, src2]);
+                    },
+                    (Operand::Register(src1), Operand::Immediate(src2)) => {
+                        let src2 = width(opcode)(src2);
+
+                        inst.extend_from_slice(&[0b01 << 6 | src2.len() as u8, dst, src1]);
+                        inst.extend_from_slice(&src2);
+                    },
+                    (Operand::Immediate(src1), Operand::Immediate(src2)) => {
+                        // Both immediates share one width
+                        let len = usize::max(width(opcode)(src1).len(), width(opcode)(src2).len());
+
+                        inst.extend_from_slice(&[0b10 << 6 | len as u8, dst]);
+                        inst.extend_from_slice(&resize(opcode, src1, len));
+                        inst.extend_from_slice(&resize(opcode, src2, len));
+                    },
+                    _ => return Err(error(token, &format!("unsupported operands for {}", mnemonic)))
+                }
+            },
+            "not" | "itof" | "ftoi" => {
+                let opcode = match mnemonic.as_str() {
+                    "not" => Opcode::NOT,
+                    "itof" => Opcode::ITOF,
+                    _ => Opcode::FTOI
+                };
+
+                let dst = match self.parse_operand()? {
+                    Operand::Register(dst) => dst,
+                    _ => return Err(error(token, &format!("{} expects a destination register", mnemonic)))
+                };
+
+                match self.parse_typed(opcode)? {
+                    Operand::Register(src) => {
+                        inst.extend_from_slice(&[opcode as u8, 0b00 << 6, dst, src]);
+                    },
+                    Operand::Immediate(src) => {
+                        let src = width(opcode)(src);
+
+                        inst.extend_from_slice(&[opcode as u8, 0b01 << 6 | src.len() as u8, dst]);
+                        inst.extend_from_slice(&src);
+                    },
+                    _ => return Err(error(token, &format!("{} expects a register or immediate", mnemonic)))
+                }
+            },
+            "loadb" | "loadh" | "loadw" | "storeb" | "storeh" | "storew" => {
+                let opcode = match mnemonic.as_str() {
+                    "loadb" => Opcode::LOADB,
+                    "loadh" => Opcode::LOADH,
+                    "loadw" => Opcode::LOADW,
+                    "storeb" => Opcode::STOREB,
+                    "storeh" => Opcode::STOREH,
+                    _ => Opcode::STOREW
+                };
+
+                let reg = match self.parse_operand()? {
+                    Operand::Register(reg) => reg,
+                    _ => return Err(error(token, &format!("{} expects a register first", mnemonic)))
+                };
+
+                // Addresses are in bytes, like jump targets
+                match self.parse_target()? {
+                    Operand::Register(addr) => {
+                        inst.extend_from_slice(&[opcode as u8, 0b00 << 6, reg, addr]);
+                    },
+                    Operand::Address(addr) => {
+                        let addr = addr_bytes(addr);
+
+                        inst.extend_from_slice(&[opcode as u8, 0b01 << 6 | addr.len() as u8, reg]);
+                        inst.extend_from_slice(&addr);
+                    },
+                    _ => return Err(error(token, &format!("{} expects a register or address", mnemonic)))
+                }
+            },
+            "cal" => {
+                let call = self.operand()?;
+
+                let code = match call.r#type {
+                    TokenType::WORD => match self.call(&call.val) {
+                        Some(code) => code,
+                        None => return Err(error(call, &format!("unknown call {}", call.val)))
+                    },
+                    TokenType::NUMBER => {
+                        let code = parse_number(call, &call.val)?;
+
+                        if code > 0xFF {
+                            return Err(error(call, &format!("call {} is out of range", call.val)));
+                        }
+
+                        code as u8
+                    },
+                    _ => return Err(error(call, &format!("unknown call {}", call.val)))
+                };
+
+                inst.extend_from_slice(&[Opcode::CAL as u8, code]);
+            },
+            "flx" => {
+                // Load the module named by the string at path, moved up
+                // by base words, with the load status in the register
+                let operands = (self.parse_operand()?, self.parse_operand()?, self.parse_operand()?);
+
+                match operands {
+                    (Operand::Register(status), Operand::Address(path), Operand::Address(base)) => {
+                        let path = addr_bytes(path);
+                        let base = addr_bytes(base);
+
+                        inst.push(Opcode::FILE_LOAD as u8);
+                        inst.push((path.len() << 4 | base.len()) as u8);
+                        inst.push(status);
+                        inst.extend_from_slice(&path);
+                        inst.extend_from_slice(&base);
+                    },
+                    _ => return Err(error(token, "flx expects a register and two addresses"))
+                }
+            },
+            _ => return Err(error(token, &format!("{} is not supported yet", mnemonic)))
+        }
+
+        Ok(inst)
+    }
+
+    fn directive(&mut self, buf: &mut Vec<u8>) -> Result<(), AsmError> {
+        let token = self.cur();
+
+        match token.val.to_uppercase().as_str() {
+            // Continue assembling from the given word address
+            "#LFH" => {
+                let addr = match self.parse_operand()? {
+                    Operand::Address(addr) => addr,
+                    _ => return Err(error(token, "#LFH expects an address"))
+                };
+
+                if addr < self.addr {
+                    return Err(error(token, &format!("#LFH [{:#X}] is behind the current address", addr)));
+                }
+
+                if addr > MAX_LFH {
+                    return Err(error(token, &format!("#LFH [{:#X}] is past the largest address {:#X}", addr, MAX_LFH)));
+                }
+
+                buf.resize(addr as usize * 8, 0);
+            },
+            // One word, big endian
+            "#DAT" => {
+                match self.parse_operand()? {
+                    Operand::Immediate(num) => buf.extend_from_slice(&num.to_be_bytes()),
+                    Operand::Float(num) => buf.extend_from_slice(&num.to_bits().to_be_bytes()),
+                    _ => return Err(error(token, "#DAT expects a number"))
+                }
+            },
+            // Null terminated UTF-16 BE string
+            "#STR" => {
+                let string = self.operand()?;
+
+                if string.r#type != TokenType::STRING {
+                    return Err(error(string, "#STR expects a string"));
+                }
+
+                for chr in string.val.encode_utf16().chain(Some(0)) {
+                    buf.extend_from_slice(&chr.to_be_bytes());
+                }
+            },
+            _ => return Err(error(token, &format!("unknown directive {}", token.val)))
+        }
+
+        Ok(())
+    }
+}
 
-        buf
+fn error(token: &Token, message: &str) -> AsmError {
+    AsmError {
+        line: token.line,
+        message: message.to_owned()
+    }
+}
+
+fn parse_number(token: &Token, string: &str) -> Result<u64, AsmError> {
+    // Parses a number with an optional 0x, 0o or 0b prefix. Negative
+    // numbers are returned in two's complement.
+    if let Some(magnitude) = string.strip_prefix('-') {
+        let num = parse_number(token, magnitude)?;
+
+        if num > 1 << 63 {
+            return Err(error(token, &format!("{} is out of range", string)));
+        }
+
+        return Ok(num.wrapping_neg());
+    }
+
+    let (digits, radix) = match string.get(..2) {
+        Some("0x") => (&string[2..], 16),
+        Some("0o") => (&string[2..], 8),
+        Some("0b") => (&string[2..], 2),
+        _ => (string, 10)
+    };
+
+    u64::from_str_radix(&digits.replace('_', ""), radix)
+        .map_err(|_| error(token, &format!("invalid number {}", string)))
+}
+
+fn is_float(string: &str) -> bool {
+    // Decimal numbers with a fraction or exponent are floats
+    let string = string.trim_start_matches('-');
+    let prefixed = ["0x", "0o", "0b"].iter().any(|prefix| string.starts_with(prefix));
+
+    !prefixed && string.contains(['.', 'e', 'E'])
+}
+
+fn imm_bytes(num: u64) -> Vec<u8> {
+    // Smallest big endian representation of num, at least one byte
+    let bytes = num.to_be_bytes();
+    let skip = usize::min(num.leading_zeros() as usize / 8, 7);
+
+    bytes[skip..].to_vec()
+}
+
+fn simm_bytes(num: u64) -> Vec<u8> {
+    // Smallest big endian two's complement representation of num which
+    // sign extends back to it, at least one byte
+    let bytes = num.to_be_bytes();
+    let skip = (0..7)
+        .take_while(|i| u8arr_to_i64(&bytes[i + 1..]) == num as i64)
+        .count();
+
+    bytes[skip..].to_vec()
+}
+
+fn fimm_bytes(bits: u64) -> Vec<u8> {
+    // Most significant bytes of an f64 bit pattern, dropping trailing
+    // zero bytes, at least one byte
+    let bytes = bits.to_be_bytes();
+    let len = usize::max(8 - bits.trailing_zeros() as usize / 8, 1);
+
+    bytes[..len].to_vec()
+}
+
+fn width(opcode: Opcode) -> fn(u64) -> Vec<u8> {
+    // Immediates of signed opcodes are sign extended by the VM, and
+    // those of float opcodes have their low bytes filled with zeros
+    if opcode.is_signed() {
+        simm_bytes
+    } else if opcode.is_float() {
+        fimm_bytes
+    } else {
+        imm_bytes
+    }
+}
+
+fn resize(opcode: Opcode, num: u64, len: usize) -> Vec<u8> {
+    // Encode num in len bytes, where len is at least its own width
+    if opcode.is_float() {
+        num.to_be_bytes()[..len].to_vec()
+    } else {
+        num.to_be_bytes()[8 - len..].to_vec()
+    }
+}
+
+fn align(buf: &mut Vec<u8>) -> u32 {
+    // Pad buf to the next word, returning its word address
+    buf.resize(buf.len().div_ceil(8) * 8, 0);
+    (buf.len() / 8) as u32
+}
+
+fn addr_bytes(addr: u32) -> Vec<u8> {
+    imm_bytes(addr as u64)
+}
+
+fn comparison(cond: &str) -> (Opcode, Opcode) {
+    // Register/register and register/immediate opcodes for a condition
+    match cond {
+        "eq" => (Opcode::CMP_EQ_REG_REG, Opcode::CMP_EQ_REG_IMM),
+        "le" => (Opcode::CMP_LE_REG_REG, Opcode::CMP_LE_REG_IMM),
+        "ge" => (Opcode::CMP_GE_REG_REG, Opcode::CMP_GE_REG_IMM),
+        "lt" => (Opcode::CMP_LT_REG_REG, Opcode::CMP_LT_REG_IMM),
+        "ile" => (Opcode::CMP_ILE_REG_REG, Opcode::CMP_ILE_REG_IMM),
+        "ige" => (Opcode::CMP_IGE_REG_REG, Opcode::CMP_IGE_REG_IMM),
+        "ilt" => (Opcode::CMP_ILT_REG_REG, Opcode::CMP_ILT_REG_IMM),
+        "igt" => (Opcode::CMP_IGT_REG_REG, Opcode::CMP_IGT_REG_IMM),
+        "feq" => (Opcode::CMP_FEQ_REG_REG, Opcode::CMP_FEQ_REG_IMM),
+        "fle" => (Opcode::CMP_FLE_REG_REG, Opcode::CMP_FLE_REG_IMM),
+        "fge" => (Opcode::CMP_FGE_REG_REG, Opcode::CMP_FGE_REG_IMM),
+        "flt" => (Opcode::CMP_FLT_REG_REG, Opcode::CMP_FLT_REG_IMM),
+        "fgt" => (Opcode::CMP_FGT_REG_REG, Opcode::CMP_FGT_REG_IMM),
+        _ => (Opcode::CMP_GT_REG_REG, Opcode::CMP_GT_REG_IMM)
     }
 }
 
 fn is_valid_instruction(string: &str) -> bool {
     let instructions: &[&str] = &[
-        "mov", "swp", "jmp", "jsr", "ret", "cmpeq", "cmpge", "cmple", "cmpgt",
-        "cmplt", "cmpeqz", "cmpgez", "cmplez", "cmpgtz", "cmpltz", "and", "add",
-        "sub", "mul", "div", "fadd", "fsub", "fmul", "fdiv", "not", "cal", "flx"
+        "mov", "swp", "jmp", "jsr", "call", "ret", "push", "pop", "jz", "jnz", "jlt",
+        "jge", "jc", "jnc", "cmpeq", "cmpge", "cmple", "cmpgt", "cmplt", "cmpeqz", "cmpgez", "cmplez", "cmpgtz", "cmpltz", "cmpile", "cmpige",
+        "cmpilt", "cmpigt", "cmpilez", "cmpigez", "cmpiltz", "cmpigtz", "cmpfeq",
+        "cmpfle", "cmpfge", "cmpflt", "cmpfgt", "cmpfeqz", "cmpflez", "cmpfgez",
+        "cmpfltz", "cmpfgtz", "and", "or", "xor", "shl", "shr", "sar", "rol", "ror",
+        "add", "sub", "mul", "div", "rem", "iadd", "isub", "imul", "idiv", "irem",
+        "fadd", "fsub", "fmul", "fdiv", "not", "itof", "ftoi", "cal", "flx", "loadb",
+        "loadh", "loadw", "storeb", "storeh", "storew"
     ];
 
-    instructions.contains(&&string.to_lowercase().as_str())
+    instructions.contains(&string.to_lowercase().as_str())
 }
 
-fn is_valid_call(string: &str) -> bool {
-    let calls: &[&str] = &[
-        "hlt", "pnt"
-    ];
+fn is_valid_label(string: &str) -> bool {
+    // Labels start with a letter or underscore, followed by letters,
+    // digits or underscores
+    let mut chars = string.chars();
+
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        },
+        _ => false
+    }
+}
+
+fn assemble(source: &str) -> Result<Vec<u8>, AsmError> {
+    let tokens = super::tokenizer::Tokenizer::load(source).tokenize();
+    Assembler::load(&tokens).assemble()
+}
+
+#[test]
+fn test_assemble_mov() {
+    assert_eq!(assemble("MOV R04 R29").unwrap(), vec![
+        Opcode::MOV_REG_REG as u8, 4, 29, 0, 0, 0, 0, 0
+    ]);
+
+    assert_eq!(assemble("mov R00 [0x2929]").unwrap(), vec![
+        Opcode::MOV_REG_MEM as u8, 0, 0, 0, 0x29, 0x29, 0, 0
+    ]);
+
+    assert_eq!(assemble("mov [0x27] [0x2929]").unwrap(), vec![
+        Opcode::MOV_MEM_MEM as u8, 0b0001_0010, 0x27, 0x29, 0x29, 0, 0, 0
+    ]);
+
+    assert_eq!(assemble("mov [0x92CA] 0xAABBCCDDEE").unwrap(), vec![
+        Opcode::MOV_MEM_IMM as u8, 0b0010_0101, 0x92, 0xCA, 0xAA, 0xBB, 0xCC, 0xDD,
+        0xEE, 0, 0, 0, 0, 0, 0, 0
+    ]);
+}
+
+#[test]
+fn test_assemble_errors() {
+    assert_eq!(assemble("mov R00").unwrap_err().message, "missing operand");
+    assert_eq!(assemble("\nfoo R00").unwrap_err(), AsmError {
+        line: 2,
+        message: "unknown instruction foo".to_owned()
+    });
+    assert!(assemble("mov 5 R00").is_err());
+    assert!(assemble("cal nop").is_err());
+    assert!(assemble("swp R00 5").is_err());
+
+    // Gaps are padded in the output, so they are bounded
+    assert_eq!(assemble("cal hlt\n#LFH [0x0]").unwrap_err().message, "#LFH [0x0] is behind the current address");
+    assert_eq!(assemble("#LFH [0xFFFFFFFF]").unwrap_err().message, "#LFH [0xFFFFFFFF] is past the largest address 0x100000");
+    assert!(assemble("#LFH [0x100000]").is_ok());
+}
 
-    calls.contains(&&string.to_lowercase().as_str())
-}
\ No newline at end of file
+#[test]
+fn test_assemble_program() {
+    let source = "
+        mov R01 5           ; counter
+        mov R02 0
+        loop
+        add R02 R02 0x3
+        sub R01 R01 1
+        cmpgtz R01          ; loop while R01 > 0
+        jmp [loop]
+        mul R03 6 7
+        mov [0x100] R02
+        cal hlt
+    ";
+
+    let bytes = assemble(source).unwrap();
+    let mut vm = crate::bvm::VM::new();
+
+    // Instructions are packed, the loop label starts a new word
+    assert_eq!(bytes[..8], [
+        Opcode::MOV_REG_IMM as u8, 0b0001_0000, 1, 5,
+        Opcode::MOV_REG_IMM as u8, 0b0001_0000, 2, 0
+    ]);
+    assert_eq!(bytes[8], Opcode::ADD as u8);
+
+    vm.mem.write_bytes(0, &bytes);
+    assert_eq!(vm.run().unwrap(), crate::bvm::ExitReason::Halted(0));
+
+    assert_eq!(vm.mem.read(0x100).unwrap(), 15);
+    assert_eq!(vm.reg.get(&3), 42);
+}
+
+#[test]
+fn test_assemble_labels() {
+    let source = "
+        jmp [start]
+        value #LFH [0x100]
+        start
+        mov [value] 0x29
+        mov R00 [value]
+        cal hlt
+    ";
+
+    let tokens = super::tokenizer::Tokenizer::load(source).tokenize();
+    let mut assembler = Assembler::load(&tokens);
+    let bytes = assembler.assemble().unwrap();
+
+    assert_eq!(assembler.labels(), vec![("value", 1), ("start", 0x100)]);
+
+    // Forward reference, with the address width chosen from the label.
+    // Jumps take the label's byte address.
+    assert_eq!(bytes[..8], [Opcode::JMP_IMM as u8, 2, 0x08, 0x00, 0, 0, 0, 0]);
+    assert_eq!(bytes[0x800..0x804], [Opcode::MOV_MEM_IMM as u8, 0b0001_0001, 1, 0x29]);
+
+    let mut vm = crate::bvm::VM::new();
+
+    vm.mem.write_bytes(0, &bytes);
+    assert_eq!(vm.run().unwrap(), crate::bvm::ExitReason::Halted(0x29));
+}
+
+#[test]
+fn test_assemble_container() {
+    use crate::bvm::{VM, ExitReason};
+    use crate::bvm::error::Fault;
+
+    // Writes to label and #LFH buffers, then to its own code
+    let source = "
+        #LFH [0x2]
+        start
+        mov [value] 0x29
+        mov R00 [value]
+        push R00
+        mov R00 0x10
+        cal getl
+        cal hlt
+        mov [start] 0
+        cal hlt
+        value #LFH [0x10]
+        name #STR \"..........\"
+    ";
+
+    let tokens = super::tokenizer::Tokenizer::load(source).tokenize();
+    let container = Assembler::load(&tokens).container().unwrap();
+    let kinds: Vec<(SectionKind, u32, u32)> = container.sections.iter()
+        .map(|section| (section.kind, section.load, section.words()))
+        .collect();
+
+    assert_eq!(kinds, vec![(SectionKind::Data, 0, 2), (SectionKind::Code, 2, 4), (SectionKind::Data, 6, 13)]);
+    assert_eq!(container.entry, 2);
+    assert_eq!(container.symbols[0], Symbol { name: "start".to_owned(), addr: 2 });
+
+    let bytes = container.to_bytes().unwrap();
+    let io = crate::bvm::io::Buffer::new("Ada\n");
+    let mut vm = VM::with_io(Box::new(io));
+
+    vm.load(&Container::read(&bytes).unwrap()).unwrap();
+    assert_eq!(vm.run(), Ok(ExitReason::Halted(3)));
+    assert_eq!(vm.mem.read(6), Some(0x29));
+    assert_eq!(vm.mem.read_utf16(0x10), "Ada");
+
+    // Skip the first CAL HLT
+    vm.jump(2 * 8 + 21);
+    assert_eq!(vm.resume().unwrap_err().cause, Fault::NotWritable(2));
+}
+
+#[test]
+fn test_assemble_label_errors() {
+    assert_eq!(assemble("jmp [nowhere]\ncal hlt").unwrap_err(), AsmError {
+        line: 1,
+        message: "undefined label nowhere".to_owned()
+    });
+    assert_eq!(assemble("loop cal hlt\nloop cal hlt").unwrap_err(), AsmError {
+        line: 2,
+        message: "label loop is already defined".to_owned()
+    });
+    assert_eq!(assemble("9lives cal hlt").unwrap_err().message, "unexpected operand 9lives");
+}
+
+#[test]
+fn test_assemble_signed() {
+    // Signed immediates use the smallest width which sign extends back
+    assert_eq!(assemble("iadd R01 R02 -1").unwrap(), vec![
+        Opcode::IADD as u8, 0b01_000001, 1, 2, 0xFF, 0, 0, 0
+    ]);
+    assert_eq!(assemble("iadd R01 R02 0x80").unwrap(), vec![
+        Opcode::IADD as u8, 0b01_000010, 1, 2, 0x00, 0x80, 0, 0
+    ]);
+    assert_eq!(assemble("cmpilt R01 -0x81").unwrap(), vec![
+        Opcode::CMP_ILT_REG_IMM as u8, 0b0010_0000, 1, 0xFF, 0x7F, 0, 0, 0
+    ]);
+
+    let source = "
+        mov R01 -5
+        imul R02 R01 3      ; -15
+        irem R03 R02 4      ; -3
+        cmpiltz R03
+        mov R00 1
+        cal hlt
+    ";
+
+    let mut vm = crate::bvm::VM::new();
+
+    vm.mem.write_bytes(0, &assemble(source).unwrap());
+    assert_eq!(vm.run().unwrap(), crate::bvm::ExitReason::Halted(1));
+    assert_eq!(vm.reg.get(&3) as i64, -3);
+}
+
+#[test]
+fn test_assemble_float() {
+    // 1.5 is 0x3FF8000000000000, only the leading bytes are encoded
+    assert_eq!(assemble("fadd R01 R02 1.5").unwrap(), vec![
+        Opcode::FADD as u8, 0b01_000010, 1, 2, 0x3F, 0xF8, 0, 0
+    ]);
+    // Integers are converted for float instructions
+    assert_eq!(assemble("cmpfgt R01 2").unwrap(), vec![
+        Opcode::CMP_FGT_REG_IMM as u8, 0b0001_0000, 1, 0x40, 0, 0, 0, 0
+    ]);
+    assert_eq!(assemble("add R01 R02 1.5").unwrap_err().message, "float 1.5 is only allowed in float instructions");
+
+    let source = "
+        mov R01 1.5
+        fadd R02 R01 2.25   ; 3.75
+        fmul R02 R02 -2     ; -7.5
+        ftoi R03 R02        ; -7
+        itof R04 10
+        cmpfltz R02
+        mov R00 1
+        cal hlt
+    ";
+
+    let mut vm = crate::bvm::VM::new();
+
+    vm.mem.write_bytes(0, &assemble(source).unwrap());
+    assert_eq!(vm.run().unwrap(), crate::bvm::ExitReason::Halted(1));
+    assert_eq!(f64::from_bits(vm.reg.get(&2)), -7.5);
+    assert_eq!(vm.reg.get(&3) as i64, -7);
+    assert_eq!(f64::from_bits(vm.reg.get(&4)), 10.0);
+}
+
+#[test]
+fn test_assemble_stack() {
+    let source = "
+        mov R01 10
+        call [fib]
+        mov R00 R02
+        cal hlt
+
+        ; R02 = fib(R01), using the stack to keep R01 across calls
+        fib
+        mov R02 R01
+        cmplt R01 2
+        ret
+        push R01
+        sub R01 R01 1
+        call [fib]
+        pop R01
+        push R02
+        sub R01 R01 2
+        call [fib]
+        pop R03
+        add R02 R02 R03
+        ret
+    ";
+
+    let mut vm = crate::bvm::VM::new();
+
+    vm.mem.write_bytes(0, &assemble(source).unwrap());
+    assert_eq!(vm.run().unwrap(), crate::bvm::ExitReason::Halted(55));
+}
+
+#[test]
+fn test_assemble_branch() {
+    assert_eq!(assemble("jnz -3").unwrap(), vec![
+        Opcode::JNZ as u8, 0x81, 0xFD, 0, 0, 0, 0, 0
+    ]);
+    assert_eq!(assemble("jc [0x1234]").unwrap(), vec![
+        Opcode::JC as u8, 2, 0x12, 0x34, 0, 0, 0, 0
+    ]);
+
+    let source = "
+        mov R01 0x10
+        loop
+        add R02 R02 3
+        sub R01 R01 1
+        jnz [loop]
+        cmpeq R02 48
+        jz 4
+        mov R00 1           ; 4 bytes, skipped by jz
+        cal hlt
+    ";
+
+    let mut vm = crate::bvm::VM::new();
+
+    vm.mem.write_bytes(0, &assemble(source).unwrap());
+    assert_eq!(vm.run().unwrap(), crate::bvm::ExitReason::Halted(0));
+    assert_eq!(vm.reg.get(&2), 48);
+}
+
+#[test]
+fn test_assemble_io() {
+    let source = "
+        mov R00 0x40
+        cal pnt             ; prompt
+        mov R00 0x80
+        cal getl
+        mov R00 0x80
+        cal pnt
+        mov R00 0x21
+        cal pntc            ; !
+        cal geti
+        imul R00 R00 2
+        cal pnti
+        mov R00 10
+        cal pntc
+        mov R00 2.5
+        cal pntf
+        mov R00 0x40
+        cal epnt
+        cal getc            ; no input left
+        jc [done]
+        mov R00 1
+        done
+        cal hlt
+
+        #LFH [0x40]
+        #STR \"name? \"
+    ";
+
+    let io = crate::bvm::io::Buffer::new("Ada\n21\n");
+    let mut vm = crate::bvm::VM::with_io(Box::new(io.clone()));
+
+    vm.mem.write_bytes(0, &assemble(source).unwrap());
+    assert_eq!(vm.run().unwrap(), crate::bvm::ExitReason::Halted(0));
+    assert_eq!(io.output(), "name? Ada!42\n2.5");
+    assert_eq!(io.error(), "name? ");
+}
+
+#[test]
+fn test_assemble_load_store() {
+    // Uppercase a string one UTF-16 character at a time
+    let source = "
+        loadw R05 [text]
+        mov R01 0x200
+        loop
+        loadh R02 R01
+        cmpeqz R02
+        jmp [done]
+        cmpge R02 0x61
+        jmp [lower]
+        jmp [next]
+        lower
+        cmple R02 0x7A
+        sub R02 R02 0x20
+        storeh R02 R01
+        next
+        add R01 R01 2
+        jmp [loop]
+        done
+        mov R00 0x40
+        cal pnt
+        cal hlt
+
+        #LFH [0x40]
+        text
+        #STR \"hello, world\"
+    ";
+
+    assert_eq!(&assemble(source).unwrap()[..6], &[Opcode::LOADW as u8, 0b01_000010, 5, 0x02, 0x00, Opcode::MOV_REG_IMM as u8]);
+
+    let io = crate::bvm::io::Buffer::default();
+    let mut vm = crate::bvm::VM::with_io(Box::new(io.clone()));
+
+    vm.mem.write_bytes(0, &assemble(source).unwrap());
+    assert_eq!(vm.run().unwrap(), crate::bvm::ExitReason::Halted(0x40));
+    assert_eq!(io.output(), "HELLO, WORLD");
+    assert_eq!(vm.reg.get(&5), 0x0068_0065_006C_006C);
+
+    assert_eq!(assemble("loadb [0x10] R01").unwrap_err().message, "loadb expects a register first");
+    assert_eq!(assemble("storeh R01 0x10").unwrap_err().message, "storeh expects a register or address");
+}
+
+#[test]
+fn test_assemble_host_calls() {
+    let mut vm = crate::bvm::VM::new();
+    vm.register(0x10, "clock", |reg, _| {
+        reg.set(0, 29);
+        Ok(())
+    }).unwrap();
+
+    let tokens = super::tokenizer::Tokenizer::load("cal clock\ncal hlt").tokenize();
+    assert_eq!(Assembler::load(&tokens).assemble().unwrap_err().message, "unknown call clock");
+
+    let bytes = Assembler::for_vm(&tokens, &vm).assemble().unwrap();
+
+    assert_eq!(bytes[..2], [Opcode::CAL as u8, 0x10]);
+
+    vm.mem.write_bytes(0, &bytes);
+    assert_eq!(vm.run().unwrap(), crate::bvm::ExitReason::Halted(29));
+}
@@ -0,0 +1,483 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt::Write;
+use crate::bvm::Decoded;
+use crate::bvm::container::{Container, Section, SectionKind};
+use crate::bvm::error::Fault;
+use crate::bvm::externals::{u8arr_to_u32, u8arr_to_u64, u8arr_to_i64};
+use crate::bvm::instructions::{Instruction, Opcode};
+use crate::bvm::memory::Memory;
+use crate::bvm::CALLS;
+
+pub fn disassemble(container: &Container) -> String {
+    // Render every section of a container as basm. Code sections are
+    // decoded one instruction per line, data sections become #DAT words
+    // with #LFH over runs of zeros, and symbols become labels at the
+    // words they name. Assembling the output of asm gives back the same
+    // container.
+    let mem = Memory::new();
+    let mut labels: BTreeMap<u32, Vec<&str>> = BTreeMap::new();
+    let mut sections: Vec<&Section> = container.sections.iter().collect();
+    let mut listing = String::new();
+    // Word address just past the last section, which may be 1 << 32
+    let mut end: u64 = 0;
+
+    for section in &container.sections {
+        mem.write_bytes(section.load, &section.bytes);
+    }
+
+    for symbol in &container.symbols {
+        labels.entry(symbol.addr).or_default().push(&symbol.name);
+    }
+
+    sections.sort_by_key(|section| section.load);
+
+    for section in sections {
+        if section.load as u64 > end {
+            let _ = writeln!(listing, "#LFH [{:#X}]", section.load);
+        }
+
+        match section.kind {
+            SectionKind::Code => code(&mem, section, &labels, &mut listing),
+            SectionKind::Data => data(&mem, section, &labels, &mut listing)
+        }
+
+        end = section.load as u64 + section.words() as u64;
+    }
+
+    if let Ok(end) = u32::try_from(end) {
+        label(&labels, end, &mut listing);
+    }
+
+    listing
+}
+
+fn code(mem: &Memory, section: &Section, labels: &BTreeMap<u32, Vec<&str>>, listing: &mut String) {
+    // Render the instructions of a code section. Gaps between them are
+    // restored by a label or #LFH, which both start a new word.
+    let end = section.load as u64 + section.words() as u64;
+    let mut addr = section.load;
+    let mut offset = 0;
+    // Byte address the assembler would place the next instruction at
+    let mut next = section.load as u64 * 8;
+
+    loop {
+        let decoded = match Decoded::read(mem, addr, offset) {
+            Ok(decoded) if (decoded.addr as u64) < end => decoded,
+            Ok(_) => break,
+            Err(err) if err.cause == Fault::EndOfProgram => break,
+            Err(err) => {
+                let _ = writeln!(listing, "; {}", err);
+                break;
+            }
+        };
+
+        let pc = decoded.addr as u64 * 8 + decoded.offset as u64;
+
+        if decoded.offset == 0 && !label(labels, decoded.addr, listing) && pc > next {
+            let _ = writeln!(listing, "#LFH [{:#X}]", decoded.addr);
+        }
+
+        let text = render(decoded.instruction());
+        let _ = writeln!(listing, "{:<32}; {:#010X}", text, pc);
+
+        let (end_addr, end_offset) = decoded.end();
+        addr = end_addr;
+        offset = end_offset;
+        next = end_addr as u64 * 8 + end_offset as u64;
+    }
+}
+
+fn data(mem: &Memory, section: &Section, labels: &BTreeMap<u32, Vec<&str>>, listing: &mut String) {
+    // Render a data section as one #DAT per word, skipping runs of zero
+    // words with #LFH. Runs stop at labels so those keep their address.
+    let end = section.load as u64 + section.words() as u64;
+    let mut addr = section.load as u64;
+
+    while addr < end {
+        label(labels, addr as u32, listing);
+
+        let word = mem.read(addr as u32).unwrap_or(0);
+
+        if word == 0 {
+            let mut zeros = addr + 1;
+
+            while zeros < end && mem.read(zeros as u32) == Some(0) && !labels.contains_key(&(zeros as u32)) {
+                zeros += 1;
+            }
+
+            let _ = writeln!(listing, "{:<32}; {:#010X}", format!("#LFH [{:#X}]", zeros), addr * 8);
+            addr = zeros;
+        } else {
+            let _ = writeln!(listing, "{:<32}; {:#010X}", format!("#DAT {:#018X}", word), addr * 8);
+            addr += 1;
+        }
+    }
+}
+
+fn label(labels: &BTreeMap<u32, Vec<&str>>, addr: u32, listing: &mut String) -> bool {
+    // Write the labels naming addr, returns whether there were any
+    match labels.get(&addr) {
+        Some(names) => {
+            for name in names {
+                let _ = writeln!(listing, "{}", name);
+            }
+
+            true
+        },
+        None => false
+    }
+}
+
+pub fn render(inst: Instruction) -> String {
+    // Render a single instruction as basm. Instructions without a basm
+    // form are rendered as a comment holding their bytes.
+    let bytes = inst.bytes;
+
+    match inst.opcode {
+        Opcode::MOV_REG_REG => format!("MOV {} {}", reg(bytes[1]), reg(bytes[2])),
+        Opcode::MOV_REG_MEM => format!("MOV {} {}", reg(bytes[1]), addr(&bytes[2..6])),
+        Opcode::MOV_MEM_REG => format!("MOV {} {}", addr(&bytes[1..5]), reg(bytes[5])),
+        Opcode::MOV_MEM_MEM => {
+            let d = 2 + (bytes[1] >> 4) as usize;
+            format!("MOV {} {}", addr(&bytes[2..d]), addr(&bytes[d..]))
+        },
+        Opcode::MOV_REG_IMM => format!("MOV {} {}", reg(bytes[2]), imm(&bytes[3..])),
+        Opcode::MOV_MEM_IMM => {
+            let d = 2 + (bytes[1] >> 4) as usize;
+            format!("MOV {} {}", addr(&bytes[2..d]), imm(&bytes[d..]))
+        },
+        Opcode::SWP => {
+            let d = 2 + (bytes[1] >> 4) as usize;
+            format!("SWP {} {}", operand(&bytes[2..d]), operand(&bytes[d..]))
+        },
+        Opcode::JMP_IMM => format!("JMP {}", addr(&bytes[2..])),
+        Opcode::JSR => format!("JSR {}", addr(&bytes[2..])),
+        Opcode::JMP_REG => format!("JMP {}", reg(bytes[1])),
+        Opcode::CALL => format!("CALL {}", addr(&bytes[2..])),
+        Opcode::RET => "RET".to_owned(),
+        Opcode::JZ |
+        Opcode::JNZ |
+        Opcode::JLT |
+        Opcode::JGE |
+        Opcode::JC |
+        Opcode::JNC if bytes[1] & 0x80 != 0 => {
+            format!("{} {}", mnemonic(inst.opcode), u8arr_to_i64(&bytes[2..]))
+        },
+        Opcode::JZ |
+        Opcode::JNZ |
+        Opcode::JLT |
+        Opcode::JGE |
+        Opcode::JC |
+        Opcode::JNC => format!("{} {}", mnemonic(inst.opcode), addr(&bytes[2..])),
+        Opcode::PUSH if bytes[1] >> 6 == 0b00 => format!("PUSH {}", reg(bytes[2])),
+        Opcode::PUSH => format!("PUSH {}", imm(&bytes[2..])),
+        Opcode::POP => format!("POP {}", reg(bytes[1])),
+        Opcode::CMP_EQ_REG_REG |
+        Opcode::CMP_LE_REG_REG |
+        Opcode::CMP_GE_REG_REG |
+        Opcode::CMP_LT_REG_REG |
+        Opcode::CMP_GT_REG_REG |
+        Opcode::CMP_ILE_REG_REG |
+        Opcode::CMP_IGE_REG_REG |
+        Opcode::CMP_ILT_REG_REG |
+        Opcode::CMP_IGT_REG_REG |
+        Opcode::CMP_FEQ_REG_REG |
+        Opcode::CMP_FLE_REG_REG |
+        Opcode::CMP_FGE_REG_REG |
+        Opcode::CMP_FLT_REG_REG |
+        Opcode::CMP_FGT_REG_REG => {
+            format!("{} {} {}", mnemonic(inst.opcode), reg(bytes[1]), reg(bytes[2]))
+        },
+        Opcode::CMP_EQ_REG_IMM |
+        Opcode::CMP_LE_REG_IMM |
+        Opcode::CMP_GE_REG_IMM |
+        Opcode::CMP_LT_REG_IMM |
+        Opcode::CMP_GT_REG_IMM |
+        Opcode::CMP_ILE_REG_IMM |
+        Opcode::CMP_IGE_REG_IMM |
+        Opcode::CMP_ILT_REG_IMM |
+        Opcode::CMP_IGT_REG_IMM |
+        Opcode::CMP_FEQ_REG_IMM |
+        Opcode::CMP_FLE_REG_IMM |
+        Opcode::CMP_FGE_REG_IMM |
+        Opcode::CMP_FLT_REG_IMM |
+        Opcode::CMP_FGT_REG_IMM => {
+            if bytes[1] >> 4 == 0 {
+                // Empty immediates compare against zero
+                format!("{}Z {}", mnemonic(inst.opcode), reg(bytes[2]))
+            } else {
+                format!("{} {} {}", mnemonic(inst.opcode), reg(bytes[2]), operand_imm(inst.opcode, &bytes[3..]))
+            }
+        },
+        Opcode::AND |
+        Opcode::OR |
+        Opcode::XOR |
+        Opcode::SHL |
+        Opcode::SHR |
+        Opcode::SAR |
+        Opcode::ROL |
+        Opcode::ROR |
+        Opcode::ADD |
+        Opcode::SUB |
+        Opcode::MUL |
+        Opcode::DIV |
+        Opcode::REM |
+        Opcode::IADD |
+        Opcode::ISUB |
+        Opcode::IMUL |
+        Opcode::IDIV |
+        Opcode::IREM |
+        Opcode::FADD |
+        Opcode::FSUB |
+        Opcode::FMUL |
+        Opcode::FDIV => {
+            let name = mnemonic(inst.opcode);
+
+            match bytes[1] >> 6 {
+                0b00 => format!("{} {} {} {}", name, reg(bytes[2]), reg(bytes[3]), reg(bytes[4])),
+                0b01 => format!("{} {} {} {}", name, reg(bytes[2]), reg(bytes[3]), operand_imm(inst.opcode, &bytes[4..])),
+                _ => {
+                    let d = 3 + (bytes[1] & 0xF) as usize;
+                    let src1 = operand_imm(inst.opcode, &bytes[3..d]);
+                    format!("{} {} {} {}", name, reg(bytes[2]), src1, operand_imm(inst.opcode, &bytes[d..]))
+                }
+            }
+        },
+        Opcode::NOT |
+        Opcode::ITOF |
+        Opcode::FTOI => {
+            let name = mnemonic(inst.opcode);
+
+            match bytes[1] >> 6 {
+                0b00 => format!("{} {} {}", name, reg(bytes[2]), reg(bytes[3])),
+                _ => format!("{} {} {}", name, reg(bytes[2]), operand_imm(inst.opcode, &bytes[3..]))
+            }
+        },
+        Opcode::CAL => match CALLS.iter().find(|(_, code)| *code == bytes[1]) {
+            Some((name, _)) => format!("CAL {}", name.to_uppercase()),
+            None => format!("CAL {:#04X}", bytes[1])
+        },
+        Opcode::LOADB |
+        Opcode::LOADH |
+        Opcode::LOADW |
+        Opcode::STOREB |
+        Opcode::STOREH |
+        Opcode::STOREW => {
+            let name = mnemonic(inst.opcode);
+
+            match bytes[1] >> 6 {
+                0b00 => format!("{} {} {}", name, reg(bytes[2]), reg(bytes[3])),
+                _ => format!("{} {} {}", name, reg(bytes[2]), addr(&bytes[3..]))
+            }
+        },
+        Opcode::FILE_LOAD => {
+            let p = 3 + (bytes[1] >> 4) as usize;
+            format!("FLX {} {} {}", reg(bytes[2]), addr(&bytes[3..p]), addr(&bytes[p..]))
+        },
+        _ => format!("; {}", inst)
+    }
+}
+
+fn mnemonic(opcode: Opcode) -> &'static str {
+    match opcode {
+        Opcode::CMP_EQ_REG_REG | Opcode::CMP_EQ_REG_IMM => "CMPEQ",
+        Opcode::CMP_LE_REG_REG | Opcode::CMP_LE_REG_IMM => "CMPLE",
+        Opcode::CMP_GE_REG_REG | Opcode::CMP_GE_REG_IMM => "CMPGE",
+        Opcode::CMP_LT_REG_REG | Opcode::CMP_LT_REG_IMM => "CMPLT",
+        Opcode::CMP_GT_REG_REG | Opcode::CMP_GT_REG_IMM => "CMPGT",
+        Opcode::CMP_ILE_REG_REG | Opcode::CMP_ILE_REG_IMM => "CMPILE",
+        Opcode::CMP_IGE_REG_REG | Opcode::CMP_IGE_REG_IMM => "CMPIGE",
+        Opcode::CMP_ILT_REG_REG | Opcode::CMP_ILT_REG_IMM => "CMPILT",
+        Opcode::CMP_IGT_REG_REG | Opcode::CMP_IGT_REG_IMM => "CMPIGT",
+        Opcode::CMP_FEQ_REG_REG | Opcode::CMP_FEQ_REG_IMM => "CMPFEQ",
+        Opcode::CMP_FLE_REG_REG | Opcode::CMP_FLE_REG_IMM => "CMPFLE",
+        Opcode::CMP_FGE_REG_REG | Opcode::CMP_FGE_REG_IMM => "CMPFGE",
+        Opcode::CMP_FLT_REG_REG | Opcode::CMP_FLT_REG_IMM => "CMPFLT",
+        Opcode::CMP_FGT_REG_REG | Opcode::CMP_FGT_REG_IMM => "CMPFGT",
+        Opcode::AND => "AND",
+        Opcode::OR => "OR",
+        Opcode::XOR => "XOR",
+        Opcode::SHL => "SHL",
+        Opcode::SHR => "SHR",
+        Opcode::SAR => "SAR",
+        Opcode::ROL => "ROL",
+        Opcode::ROR => "ROR",
+        Opcode::ADD => "ADD",
+        Opcode::SUB => "SUB",
+        Opcode::MUL => "MUL",
+        Opcode::DIV => "DIV",
+        Opcode::REM => "REM",
+        Opcode::IADD => "IADD",
+        Opcode::ISUB => "ISUB",
+        Opcode::IMUL => "IMUL",
+        Opcode::IDIV => "IDIV",
+        Opcode::IREM => "IREM",
+        Opcode::FADD => "FADD",
+        Opcode::FSUB => "FSUB",
+        Opcode::FMUL => "FMUL",
+        Opcode::FDIV => "FDIV",
+        Opcode::NOT => "NOT",
+        Opcode::JZ => "JZ",
+        Opcode::JNZ => "JNZ",
+        Opcode::JLT => "JLT",
+        Opcode::JGE => "JGE",
+        Opcode::JC => "JC",
+        Opcode::JNC => "JNC",
+        Opcode::ITOF => "ITOF",
+        Opcode::FTOI => "FTOI",
+        Opcode::LOADB => "LOADB",
+        Opcode::LOADH => "LOADH",
+        Opcode::LOADW => "LOADW",
+        Opcode::STOREB => "STOREB",
+        Opcode::STOREH => "STOREH",
+        Opcode::STOREW => "STOREW",
+        _ => "???"
+    }
+}
+
+fn reg(register: u8) -> String {
+    format!("R{:02}", register)
+}
+
+fn addr(bytes: &[u8]) -> String {
+    format!("[{:#X}]", u8arr_to_u32(bytes))
+}
+
+fn operand(bytes: &[u8]) -> String {
+    // SWP operands one byte wide are registers
+    if bytes.len() == 1 {
+        reg(bytes[0])
+    } else {
+        addr(bytes)
+    }
+}
+
+fn imm(bytes: &[u8]) -> String {
+    format!("{:#X}", u8arr_to_u64(bytes))
+}
+
+fn operand_imm(opcode: Opcode, bytes: &[u8]) -> String {
+    // Immediates of signed opcodes which sign extend to a negative
+    // number are rendered in decimal with a minus sign, and those of
+    // float opcodes as floats
+    let num = u8arr_to_i64(bytes);
+
+    if opcode.is_float() {
+        let bits = u8arr_to_u64(bytes).checked_shl(64 - 8 * bytes.len() as u32).unwrap_or(0);
+        format!("{:?}", f64::from_bits(bits))
+    } else if opcode.is_signed() && num < 0 {
+        num.to_string()
+    } else {
+        imm(bytes)
+    }
+}
+
+#[test]
+fn test_render() {
+    let inst = Instruction::with_data(
+        Opcode::MOV_REG_MEM,
+        &[Opcode::MOV_REG_MEM as u8, 4, 0, 0, 0x29, 0x29]
+    );
+    assert_eq!(render(inst), "MOV R04 [0x2929]");
+
+    let inst = Instruction::with_data(
+        Opcode::ADD,
+        &[Opcode::ADD as u8, 0b01_000001, 1, 2, 0x10]
+    );
+    assert_eq!(render(inst), "ADD R01 R02 0x10");
+
+    let inst = Instruction::with_data(
+        Opcode::CMP_GT_REG_IMM,
+        &[Opcode::CMP_GT_REG_IMM as u8, 0, 7]
+    );
+    assert_eq!(render(inst), "CMPGTZ R07");
+}
+
+#[test]
+fn test_disassemble_roundtrip() {
+    use super::tokenizer::Tokenizer;
+    use super::assembler::Assembler;
+
+    let source = "
+        mov R01 R02
+        mov R03 [0x2929]
+        mov [0x10] R04
+        mov [0x27] [0x2929]
+        mov R255 0xAABBCCDDEEFF0011
+        mov [0x92CA] 0xAABBCCDDEE
+        swp R01 R02
+        swp [0x10] R03
+        swp [0x10] [0xAABBCC]
+        jmp [0x12]
+        jmp R29
+        jsr [0x100]
+        jmp R255
+        call [0x100]
+        ret
+        push R01
+        push 0x1234
+        pop R254
+        jz -2
+        jnz 0x100
+        jlt [0x20]
+        jnc [0x1234]
+        cmpeq R00 R03
+        cmplt R01 0x1000
+        cmpgez R02
+        add R01 R02 R03
+        sub R01 R02 0xFFFF
+        mul R01 6 0x1234
+        fdiv R09 R08 R07
+        and R01 R02 R03
+        xor R01 R02 0xFF
+        sar R01 0x80 4
+        ror R01 R02 R03
+        rem R01 R02 7
+        iadd R01 R02 -1
+        isub R01 R02 0x80
+        imul R01 -300 5
+        idiv R01 R02 R03
+        cmpilt R01 -0x8000
+        cmpigez R01
+        mov R01 1.5
+        fadd R01 R02 0.1
+        fmul R01 -2.5 1e300
+        fdiv R01 R02 R03
+        cmpflt R01 0.5
+        cmpfeqz R01
+        itof R01 -3
+        ftoi R01 2.75
+        not R01 R02
+        not R01 0xFF
+        flx R01 [0x2929] [0x1000]
+        loadb R01 R02
+        loadh R03 [0x81]
+        storew R04 [0x100000]
+        storeb R05 R06
+        #LFH [0x40]
+        loop
+        cal pnt
+        cal getl
+        jmp [loop]
+        cal pntf
+        cal 0x42
+        cal hlt
+        buffer #LFH [0x80]
+        text #STR \"hi there\"
+        #DAT 0x29
+        #LFH [0x90]
+        #DAT -1.5
+        end
+    ";
+
+    let tokens = Tokenizer::load(source).tokenize();
+    let container = Assembler::load(&tokens).container().unwrap();
+
+    let listing = disassemble(&container);
+    let tokens = Tokenizer::load(&listing).tokenize();
+    let reassembled = Assembler::load(&tokens).container().unwrap();
+
+    assert_eq!(container.to_bytes(), reassembled.to_bytes(), "\n{}", listing);
+    assert!(listing.contains("#DAT 0x0068006900200074"));
+}
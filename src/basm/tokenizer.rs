@@ -2,13 +2,15 @@
 
 pub struct Token {
     pub r#type: TokenType,
-    pub val: String
+    pub val: String,
+    pub line: usize
 }
 
 pub struct Tokenizer<'a> {
     tokens: &'a [Token],
-    data: &'a str,
-    pos: usize
+    chars: Vec<char>,
+    pos: usize,
+    line: usize
 }
 
 #[derive(PartialEq, Debug)]
@@ -26,61 +28,87 @@ impl<'a> Tokenizer<'a> {
     pub fn load(data: &'a str) -> Tokenizer<'a> {
         Tokenizer {
             tokens: &[],
-            data: data,
-            pos: 0
+            chars: data.chars().collect(),
+            pos: 0,
+            line: 1
         }
     }
 
     fn cur(&self) -> char {
-        self.data.chars().nth(self.pos).unwrap()
+        // Current character, or NUL once past the end of the input
+        self.chars.get(self.pos).copied().unwrap_or('\0')
     }
 
     fn peak(&self) -> char {
-        self.data.chars().nth(self.pos + 1).unwrap()
+        self.chars.get(self.pos + 1).copied().unwrap_or('\0')
     }
 
     fn next(&mut self) -> char {
-        self.pos += 1;
+        self.advance();
         self.cur()
     }
 
+    fn advance(&mut self) {
+        // Move past the current character, counting lines as they end
+        if self.cur() == '\n' {
+            self.line += 1;
+        }
+
+        self.pos += 1;
+    }
+
     pub fn tokenize(&mut self) -> Vec<Token> {
         let mut tokens: Vec<Token> = Vec::with_capacity(128);
 
-        while self.pos < self.data.len() {
+        while self.pos < self.chars.len() {
+            // Tokens record the line they start on for error reporting
+            let line = self.line;
+
             match self.cur() {
                 '#' => {
                     tokens.push(Token {
                         r#type: TokenType::DIRECTIVE,
-                        val: self.match_until_whitespace()
+                        val: self.match_until_whitespace(),
+                        line
                     });
                 },
                 '[' => {
                     tokens.push(Token {
                         r#type: TokenType::ADDRESS,
-                        val: self.match_until(']')
+                        val: self.match_until(']'),
+                        line
                     });
                 },
                 'R' if self.peak().is_numeric() => {
-                    self.pos += 1;
+                    self.advance();
 
                     tokens.push(Token {
                         r#type: TokenType::REGISTER,
-                        val: self.match_until_whitespace()
+                        val: self.match_until_whitespace(),
+                        line
                     });
                 },
                 '0' if ['x', 'o', 'b'].contains(&self.peak()) => {
                     tokens.push(Token {
                         r#type: TokenType::NUMBER,
-                        val: self.match_until_whitespace()
+                        val: self.match_until_whitespace(),
+                        line
+                    });
+                },
+                '-' if self.peak().is_numeric() => {
+                    tokens.push(Token {
+                        r#type: TokenType::NUMBER,
+                        val: self.match_until_whitespace(),
+                        line
                     });
                 },
                 '"' => {
-                    self.pos += 1;
+                    self.advance();
 
                     tokens.push(Token {
                         r#type: TokenType::STRING,
-                        val: self.match_until('"')
+                        val: self.match_until('"'),
+                        line
                     });
                 },
                 ';' => {
@@ -89,21 +117,21 @@ impl<'a> Tokenizer<'a> {
                 _ if self.cur().is_numeric() => {
                     tokens.push(Token {
                         r#type: TokenType::NUMBER,
-                        val: self.match_until_whitespace()
+                        val: self.match_until_whitespace(),
+                        line
                     })
                 },
-                _ if self.cur().is_whitespace() => {
-                    self.next();
-                }
+                _ if self.cur().is_whitespace() => {}
                 _ => {
                     tokens.push(Token {
                         r#type: TokenType::WORD,
-                        val: self.match_until_whitespace()
+                        val: self.match_until_whitespace(),
+                        line
                     })
                 }
             }
 
-            self.pos += 1;
+            self.advance();
         }
 
         tokens
@@ -112,14 +140,14 @@ impl<'a> Tokenizer<'a> {
     fn match_until_whitespace(&mut self) -> String {
         let mut string = String::new();
 
-        while !self.cur().is_whitespace() {
+        while self.pos < self.chars.len() && !self.cur().is_whitespace() {
             if self.cur() == ';' {
                 self.match_until('\n');
                 break;
             }
 
             string.push(self.cur());
-            self.pos += 1;
+            self.advance();
         }
 
         string
@@ -128,9 +156,9 @@ impl<'a> Tokenizer<'a> {
     fn match_until(&mut self, end: char) -> String {
         let mut string = String::new();
 
-        while self.cur() != end {
+        while self.pos < self.chars.len() && self.cur() != end {
             string.push(self.cur());
-            self.pos += 1;
+            self.advance();
         }
 
         string
@@ -155,4 +183,23 @@ fn test_tokenizer() {
     assert_eq!(tokens[8].r#type, TokenType::WORD);
     assert_eq!(tokens[9].r#type, TokenType::DIRECTIVE);
     assert_eq!(tokens[10].r#type, TokenType::STRING);
-}
\ No newline at end of file
+
+    // Lines ending inside strings and comments are counted
+    assert_eq!(tokens.iter().map(|token| token.line).collect::<Vec<_>>(), vec![1, 1, 2, 2, 3, 3, 3, 3, 4, 4, 4]);
+
+    let tokens = Tokenizer::load("#STR \"a\nb\" ; c\nCAL HLT").tokenize();
+    assert_eq!(tokens[2].line, 3);
+}
+
+#[test]
+fn test_tokenizer_whitespace() {
+    let data = "MOV  R01\t\t0x10\n\n  CAL HLT";
+    let mut tokenizer = Tokenizer::load(data);
+    let tokens = tokenizer.tokenize();
+
+    assert_eq!(tokens.len(), 5);
+    assert_eq!(tokens[1].val, "01");
+    assert_eq!(tokens[2].val, "0x10");
+    assert_eq!(tokens[3].line, 3);
+    assert_eq!(tokens[4].val, "HLT");
+}
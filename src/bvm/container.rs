@@ -0,0 +1,345 @@
+extern crate byteorder;
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{Cursor, Read};
+use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
+
+// Layout, all integers are big endian:
+//   magic "BRDN", version u16, section count u16, entry u32, symbol count u32
+//   section: kind u8, load address u32, length u32, bytes
+//   symbol: address u32, name length u16, UTF-8 name
+pub const MAGIC: [u8; 4] = *b"BRDN";
+pub const VERSION: u16 = 1;
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum SectionKind {
+    Code = 0,
+    Data = 1
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Section {
+    pub kind: SectionKind,
+    // Word address the section is loaded at
+    pub load: u32,
+    pub bytes: Vec<u8>
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub addr: u32
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Container {
+    pub version: u16,
+    // Word address execution starts from
+    pub entry: u32,
+    pub sections: Vec<Section>,
+    pub symbols: Vec<Symbol>
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum LoadError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    // The file ends before the header, a section or a symbol does
+    Truncated,
+    TrailingData,
+    InvalidSectionKind(u8),
+    InvalidSymbolName,
+    // A section extends past the end of the address space
+    SectionOutOfRange(u32),
+    // Two sections are loaded over the same words
+    SectionOverlap(u32, u32),
+    // The entry point is not inside a code section
+    EntryOutsideCode(u32),
+    // A count or length does not fit in its field of the layout
+    TooLarge
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::BadMagic => f.write_str("not a brandon bytecode file"),
+            LoadError::UnsupportedVersion(version) => write!(f, "unsupported format version {}", version),
+            LoadError::Truncated => f.write_str("file is truncated"),
+            LoadError::TrailingData => f.write_str("unexpected data after the symbol table"),
+            LoadError::InvalidSectionKind(kind) => write!(f, "invalid section kind {}", kind),
+            LoadError::InvalidSymbolName => f.write_str("symbol name is not valid UTF-8"),
+            LoadError::SectionOutOfRange(load) => write!(f, "section at {:#010X} is out of range", load),
+            LoadError::SectionOverlap(a, b) => write!(f, "sections at {:#010X} and {:#010X} overlap", a, b),
+            LoadError::EntryOutsideCode(entry) => write!(f, "entry point {:#010X} is not in a code section", entry),
+            LoadError::TooLarge => f.write_str("too many sections or symbols, or one is too large")
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+impl Section {
+    pub fn words(&self) -> u32 {
+        // Number of words the section occupies once loaded
+        self.bytes.len().div_ceil(8) as u32
+    }
+
+    fn end(&self) -> u64 {
+        // Word address just past the section, which may be 1 << 32
+        self.load as u64 + self.words() as u64
+    }
+}
+
+impl Container {
+    pub fn new(entry: u32) -> Container {
+        Container {
+            version: VERSION,
+            entry,
+            sections: Vec::new(),
+            symbols: Vec::new()
+        }
+    }
+
+    pub fn raw(bytes: Vec<u8>) -> Container {
+        // Wrap raw code in a container loaded and entered at address 0
+        let mut container = Container::new(0);
+
+        container.sections.push(Section {
+            kind: SectionKind::Code,
+            load: 0,
+            bytes
+        });
+
+        container
+    }
+
+    pub fn read(bytes: &[u8]) -> Result<Container, LoadError> {
+        // Parse a bytecode file. Files without the magic number are raw
+        // code loaded at address 0.
+        if Container::is_container(bytes) {
+            Container::parse(bytes)
+        } else {
+            Ok(Container::raw(bytes.to_vec()))
+        }
+    }
+
+    pub fn relocate(&mut self, base: u32) -> Result<(), LoadError> {
+        // Move the sections, symbols and entry point up by base words
+        for section in &mut self.sections {
+            section.load = section.load.checked_add(base)
+                .ok_or(LoadError::SectionOutOfRange(section.load))?;
+        }
+
+        for symbol in &mut self.symbols {
+            symbol.addr = symbol.addr.wrapping_add(base);
+        }
+
+        self.entry = self.entry.wrapping_add(base);
+        self.validate()
+    }
+
+    pub fn is_container(bytes: &[u8]) -> bool {
+        // Check for the magic number, anything else is a raw program
+        bytes.starts_with(&MAGIC)
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Container, LoadError> {
+        // Decode and validate a container
+        let mut cur = Cursor::new(bytes);
+        let mut magic = [0; 4];
+
+        cur.read_exact(&mut magic).map_err(|_| LoadError::BadMagic)?;
+
+        if magic != MAGIC {
+            return Err(LoadError::BadMagic);
+        }
+
+        let version = cur.read_u16::<BigEndian>().map_err(|_| LoadError::Truncated)?;
+
+        if version != VERSION {
+            return Err(LoadError::UnsupportedVersion(version));
+        }
+
+        let section_count = cur.read_u16::<BigEndian>().map_err(|_| LoadError::Truncated)?;
+        let entry = cur.read_u32::<BigEndian>().map_err(|_| LoadError::Truncated)?;
+        let symbol_count = cur.read_u32::<BigEndian>().map_err(|_| LoadError::Truncated)?;
+        let mut container = Container::new(entry);
+
+        for _ in 0..section_count {
+            let kind = match cur.read_u8().map_err(|_| LoadError::Truncated)? {
+                0 => SectionKind::Code,
+                1 => SectionKind::Data,
+                kind => return Err(LoadError::InvalidSectionKind(kind))
+            };
+            let load = cur.read_u32::<BigEndian>().map_err(|_| LoadError::Truncated)?;
+            let len = cur.read_u32::<BigEndian>().map_err(|_| LoadError::Truncated)?;
+
+            container.sections.push(Section {
+                kind,
+                load,
+                bytes: read_vec(&mut cur, len as usize)?
+            });
+        }
+
+        for _ in 0..symbol_count {
+            let addr = cur.read_u32::<BigEndian>().map_err(|_| LoadError::Truncated)?;
+            let len = cur.read_u16::<BigEndian>().map_err(|_| LoadError::Truncated)?;
+            let name = String::from_utf8(read_vec(&mut cur, len as usize)?)
+                .map_err(|_| LoadError::InvalidSymbolName)?;
+
+            container.symbols.push(Symbol { name, addr });
+        }
+
+        if cur.position() as usize != bytes.len() {
+            return Err(LoadError::TrailingData);
+        }
+
+        container.validate()?;
+
+        Ok(container)
+    }
+
+    pub fn validate(&self) -> Result<(), LoadError> {
+        // Check that sections fit in memory without overlapping and
+        // that the entry point is code
+        if self.version != VERSION {
+            return Err(LoadError::UnsupportedVersion(self.version));
+        }
+
+        for (i, section) in self.sections.iter().enumerate() {
+            if section.end() > 1 << 32 {
+                return Err(LoadError::SectionOutOfRange(section.load));
+            }
+
+            for other in &self.sections[..i] {
+                if (section.load as u64) < other.end() && (other.load as u64) < section.end() {
+                    return Err(LoadError::SectionOverlap(other.load, section.load));
+                }
+            }
+        }
+
+        let entry_in_code = self.sections.iter().any(|section| {
+            section.kind == SectionKind::Code
+                && section.load <= self.entry
+                && (self.entry as u64) < section.end()
+        });
+
+        if !entry_in_code {
+            return Err(LoadError::EntryOutsideCode(self.entry));
+        }
+
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, LoadError> {
+        // Encode the container, the inverse of parse. Fails rather than
+        // truncating counts and lengths too large for the layout.
+        let mut bytes: Vec<u8> = Vec::with_capacity(64);
+
+        bytes.extend_from_slice(&MAGIC);
+        let _ = bytes.write_u16::<BigEndian>(self.version);
+        let _ = bytes.write_u16::<BigEndian>(field(self.sections.len())?);
+        let _ = bytes.write_u32::<BigEndian>(self.entry);
+        let _ = bytes.write_u32::<BigEndian>(field(self.symbols.len())?);
+
+        for section in &self.sections {
+            let _ = bytes.write_u8(section.kind as u8);
+            let _ = bytes.write_u32::<BigEndian>(section.load);
+            let _ = bytes.write_u32::<BigEndian>(field(section.bytes.len())?);
+            bytes.extend_from_slice(&section.bytes);
+        }
+
+        for symbol in &self.symbols {
+            let _ = bytes.write_u32::<BigEndian>(symbol.addr);
+            let _ = bytes.write_u16::<BigEndian>(field(symbol.name.len())?);
+            bytes.extend_from_slice(symbol.name.as_bytes());
+        }
+
+        Ok(bytes)
+    }
+}
+
+fn field<T: TryFrom<usize>>(len: usize) -> Result<T, LoadError> {
+    // Narrow a count or length to the width of its field
+    T::try_from(len).map_err(|_| LoadError::TooLarge)
+}
+
+fn read_vec(cur: &mut Cursor<&[u8]>, len: usize) -> Result<Vec<u8>, LoadError> {
+    // Read exactly len bytes without trusting len for the allocation
+    let remaining = cur.get_ref().len() - cur.position() as usize;
+
+    if len > remaining {
+        return Err(LoadError::Truncated);
+    }
+
+    let mut buf = vec![0; len];
+    cur.read_exact(&mut buf).map_err(|_| LoadError::Truncated)?;
+
+    Ok(buf)
+}
+
+#[test]
+fn test_container_roundtrip() {
+    let mut container = Container::new(2);
+
+    container.sections.push(Section {
+        kind: SectionKind::Code,
+        load: 0,
+        bytes: vec![1; 20]
+    });
+    container.sections.push(Section {
+        kind: SectionKind::Data,
+        load: 0x100,
+        bytes: vec![0, 0x68, 0, 0x69]
+    });
+    container.symbols.push(Symbol {
+        name: "main".to_owned(),
+        addr: 2
+    });
+
+    let bytes = container.to_bytes().unwrap();
+
+    assert!(Container::is_container(&bytes));
+    assert_eq!(Container::parse(&bytes).unwrap(), container);
+
+    // Lengths are not truncated to fit
+    container.symbols[0].name = "x".repeat(0x10000);
+    assert_eq!(container.to_bytes(), Err(LoadError::TooLarge));
+}
+
+#[test]
+fn test_container_validate() {
+    let mut bytes = Container::new(0).to_bytes().unwrap();
+
+    assert_eq!(Container::parse(&bytes[..10]), Err(LoadError::Truncated));
+    assert_eq!(Container::parse(&bytes), Err(LoadError::EntryOutsideCode(0)));
+
+    bytes[0] = b'X';
+    assert_eq!(Container::parse(&bytes), Err(LoadError::BadMagic));
+
+    let mut container = Container::new(0);
+    container.version = 2;
+    assert_eq!(Container::parse(&container.to_bytes().unwrap()), Err(LoadError::UnsupportedVersion(2)));
+
+    let mut container = Container::new(0);
+
+    container.sections.push(Section {
+        kind: SectionKind::Code,
+        load: 0,
+        bytes: vec![1; 16]
+    });
+    container.sections.push(Section {
+        kind: SectionKind::Data,
+        load: 1,
+        bytes: vec![1; 8]
+    });
+
+    assert_eq!(container.validate(), Err(LoadError::SectionOverlap(0, 1)));
+
+    container.sections[1].load = 2;
+    assert_eq!(container.validate(), Ok(()));
+
+    container.entry = 2;
+    assert_eq!(container.validate(), Err(LoadError::EntryOutsideCode(2)));
+}
@@ -0,0 +1,94 @@
+use std::fmt;
+use super::instructions::Opcode;
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Fault {
+    // Nothing is stored at the address
+    InvalidAddress(u32),
+    // The option byte does not encode a valid operand mode or width
+    InvalidOption(u8),
+    // CAL with an unknown call number
+    UnknownCall(u8),
+    // Execution reached an empty address without CAL HLT
+    EndOfProgram,
+    Overflow,
+    Underflow,
+    DivideByZero,
+    // Pushing with the stack pointer at the bottom of the stack region
+    StackOverflow,
+    // Popping with the stack pointer at the top of the stack region
+    StackUnderflow,
+    // The address is in a region which does not allow the access
+    NotReadable(u32),
+    NotWritable(u32),
+    NotExecutable(u32),
+    // Writing to the empty address would go over the memory limit
+    QuotaExceeded(u32)
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum CallError {
+    // The code or name belongs to a built in call
+    BuiltIn(String, u8),
+    // The code is already registered under another name
+    Taken(String, u8)
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct VmError {
+    pub addr: u32,
+    pub opcode: Option<Opcode>,
+    pub cause: Fault
+}
+
+impl VmError {
+    pub fn new(addr: u32, opcode: Option<Opcode>, cause: Fault) -> VmError {
+        VmError {
+            addr,
+            opcode,
+            cause
+        }
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fault::InvalidAddress(addr) => write!(f, "memory address {:#010X} does not exist", addr),
+            Fault::InvalidOption(byte) => write!(f, "invalid option {:#04X}", byte),
+            Fault::UnknownCall(call) => write!(f, "unknown call {:#04X}", call),
+            Fault::EndOfProgram => f.write_str("reached the end of the program without HLT"),
+            Fault::Overflow => f.write_str("arithmetic overflow"),
+            Fault::Underflow => f.write_str("arithmetic underflow"),
+            Fault::DivideByZero => f.write_str("divide by zero"),
+            Fault::StackOverflow => f.write_str("stack overflow"),
+            Fault::StackUnderflow => f.write_str("stack underflow"),
+            Fault::NotReadable(addr) => write!(f, "memory address {:#010X} is not readable", addr),
+            Fault::NotWritable(addr) => write!(f, "memory address {:#010X} is not writable", addr),
+            Fault::NotExecutable(addr) => write!(f, "memory address {:#010X} is not executable", addr),
+            Fault::QuotaExceeded(addr) => write!(f, "memory limit reached writing {:#010X}", addr)
+        }
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.opcode {
+            Some(opcode) => write!(f, "fault at {:#010X} ({:?}): {}", self.addr, opcode, self.cause),
+            None => write!(f, "fault at {:#010X}: {}", self.addr, self.cause)
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
+
+impl fmt::Display for CallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CallError::BuiltIn(name, code) => write!(f, "call {} ({:#04X}) clashes with a built in call", name, code),
+            CallError::Taken(name, code) => write!(f, "call code {:#04X} is already registered as {}", code, name)
+        }
+    }
+}
+
+impl std::error::Error for CallError {}
@@ -1,24 +1,11 @@
 #![allow(dead_code)]
-extern crate libc;
 
 use std::fs;
-use libc::c_int;
+use std::io;
 
-extern "C" {
-    fn getchar() -> c_int;
-}
-
-pub fn get_char() -> i32 {
-    unsafe {
-        getchar()
-    }
-}
-
-pub fn read(path: &str) -> Vec<u8>{
+pub fn read(path: &str) -> io::Result<Vec<u8>> {
+    // Read the entire contents of a file
     fs::read(path)
-        .expect(
-            &format!("Cannot open {}", path)
-        )
 }
 
 pub fn u64_to_u8arr(int: u64) -> [u8; 8] {
@@ -29,52 +16,30 @@ pub fn u64_to_u8arr(int: u64) -> [u8; 8] {
         (int >> 32 & 0xFF) as u8,
         (int >> 24 & 0xFF) as u8,
         (int >> 16 & 0xFF) as u8,
-        (int >> 08 & 0xFF) as u8,
+        (int >>  8 & 0xFF) as u8,
         (int       & 0xFF) as u8
     ]
 }
 
 pub fn u8arr_to_u32(bytes: &[u8]) -> u32 {
     // Converts a u8 slice (len <= 4) to a u32 int
-    let mut num: u32 = 0;
-
-    for i in (0..=3).rev() {
-        let mut byte: u8 = 0;
-        let offset = 4 - bytes.len(); // use offset to preserve byte order
-
-        // Offset must <= i so we don't overflow with subtraction
-        if offset <= i && (i - offset) < bytes.len() {
-            byte = bytes[i - offset];
-        }
-
-        num |= (byte as u32) << 24 - (8 * i);
-    }
-
-    num
+    u8arr_to_u64(bytes) as u32
 }
 
 pub fn u8arr_to_u64(bytes: &[u8]) -> u64 {
     // Converts a u8 slice (len <= 8) to a u64 int
-    if bytes.len() <= 4 {
-        u8arr_to_u32(bytes) as u64
-    } else {
-        let mut num1: Vec<u8> = Vec::with_capacity(4);
-        let mut num2: Vec<u8> = Vec::with_capacity(4);
-
-        for i in 0..=3 {
-            if i < bytes.len() {
-                num1.push(bytes[i]);
-            }
-        }
-        for i in 4..=7 {
-            if i < bytes.len() {
-                num2.push(bytes[i]);
-            }
-        }
-
-        let shift = 8 * (bytes.len() - 4);
-        (u8arr_to_u32(&num1) as u64) << shift | u8arr_to_u32(&num2) as u64
+    bytes.iter().fold(0, |num, byte| num << 8 | *byte as u64)
+}
+
+pub fn u8arr_to_i64(bytes: &[u8]) -> i64 {
+    // Converts a u8 slice (len <= 8) to an i64 int, sign extending
+    // from the most significant bit of the first byte
+    if bytes.is_empty() {
+        return 0;
     }
+
+    let shift = 64 - 8 * bytes.len() as u32;
+    ((u8arr_to_u64(bytes) << shift) as i64) >> shift
 }
 
 #[test]
@@ -113,6 +78,15 @@ fn test_u8arr_to_u64() {
     )
 }
 
+#[test]
+fn test_u8arr_to_i64() {
+    assert_eq!(u8arr_to_i64(&[0xFF]), -1);
+    assert_eq!(u8arr_to_i64(&[0x7F]), 0x7F);
+    assert_eq!(u8arr_to_i64(&[0xFF, 0x00]), -0x100);
+    assert_eq!(u8arr_to_i64(&[0x80, 0, 0, 0, 0, 0, 0, 0]), i64::MIN);
+    assert_eq!(u8arr_to_i64(&[]), 0);
+}
+
 #[test]
 fn test_u64_to_u8arr() {
     let num: u64 = 0xF0E1D2C3B4A59687;
@@ -5,9 +5,12 @@ const REG: u8 = size_of::<u8>() as u8;
 const MEM: u8 = size_of::<u32>() as u8;
 const OPCODE: u8 = 1;
 const OPTION: u8 = 1;
+const IMM: u8 = size_of::<u64>() as u8;
+// Bytes in the longest instruction, arithmetic on two immediates
+pub const MAX_SIZE: usize = (OPCODE + OPTION + REG + 2 * IMM) as usize;
 
 #[allow(non_camel_case_types)]
-#[derive(PartialEq, FromPrimitive, Copy, Clone)]
+#[derive(Debug, PartialEq, FromPrimitive, Copy, Clone)]
 pub enum Opcode {
     MOV_REG_REG = 1,
     MOV_REG_MEM,
@@ -41,6 +44,63 @@ pub enum Opcode {
     NOT,
     CAL,
     FILE_LOAD,
+    OR,
+    XOR,
+    // Logical shifts fill with zeros, SAR copies the sign bit
+    SHL,
+    SHR,
+    SAR,
+    ROL,
+    ROR,
+    REM,
+    // Signed arithmetic, immediates are sign extended
+    IADD,
+    ISUB,
+    IMUL,
+    IDIV,
+    IREM,
+    CMP_ILE_REG_REG,
+    CMP_IGE_REG_REG,
+    CMP_ILT_REG_REG,
+    CMP_IGT_REG_REG,
+    CMP_ILE_REG_IMM,
+    CMP_IGE_REG_IMM,
+    CMP_ILT_REG_IMM,
+    CMP_IGT_REG_IMM,
+    // Conversions between signed integers and floats
+    ITOF,
+    FTOI,
+    // Float comparisons, false if either side is NaN
+    CMP_FEQ_REG_REG,
+    CMP_FLE_REG_REG,
+    CMP_FGE_REG_REG,
+    CMP_FLT_REG_REG,
+    CMP_FGT_REG_REG,
+    CMP_FEQ_REG_IMM,
+    CMP_FLE_REG_IMM,
+    CMP_FGE_REG_IMM,
+    CMP_FLT_REG_IMM,
+    CMP_FGT_REG_IMM,
+    // Stack operations, the stack pointer is R254
+    PUSH,
+    POP,
+    CALL,
+    RET,
+    // Conditional jumps on the flags set by arithmetic and CMP
+    JZ,
+    JNZ,
+    JLT,
+    JGE,
+    JC,
+    JNC,
+    // Byte addressed loads and stores of 1, 2 and 8 bytes. Loads zero
+    // extend, stores write the low bytes of the register.
+    LOADB,
+    LOADH,
+    LOADW,
+    STOREB,
+    STOREH,
+    STOREW,
     INVALID
 }
 
@@ -49,6 +109,68 @@ impl Opcode {
         // Convert u8 int to corresponding enum constant
         num::FromPrimitive::from_u8(num)
     }
+
+    pub fn is_signed(self) -> bool {
+        // Signed opcodes sign extend their immediates
+        matches!(self,
+            Opcode::IADD |
+            Opcode::ISUB |
+            Opcode::IMUL |
+            Opcode::IDIV |
+            Opcode::IREM |
+            Opcode::CMP_ILE_REG_REG |
+            Opcode::CMP_IGE_REG_REG |
+            Opcode::CMP_ILT_REG_REG |
+            Opcode::CMP_IGT_REG_REG |
+            Opcode::CMP_ILE_REG_IMM |
+            Opcode::CMP_IGE_REG_IMM |
+            Opcode::CMP_ILT_REG_IMM |
+            Opcode::CMP_IGT_REG_IMM |
+            Opcode::ITOF
+        )
+    }
+
+    pub fn is_float(self) -> bool {
+        // Float opcodes hold f64 bit patterns. Their immediates are the
+        // most significant bytes, with the rest of the bits zero.
+        matches!(self,
+            Opcode::FADD |
+            Opcode::FSUB |
+            Opcode::FMUL |
+            Opcode::FDIV |
+            Opcode::FTOI |
+            Opcode::CMP_FEQ_REG_REG |
+            Opcode::CMP_FLE_REG_REG |
+            Opcode::CMP_FGE_REG_REG |
+            Opcode::CMP_FLT_REG_REG |
+            Opcode::CMP_FGT_REG_REG |
+            Opcode::CMP_FEQ_REG_IMM |
+            Opcode::CMP_FLE_REG_IMM |
+            Opcode::CMP_FGE_REG_IMM |
+            Opcode::CMP_FLT_REG_IMM |
+            Opcode::CMP_FGT_REG_IMM
+        )
+    }
+
+    pub fn cost(self) -> u64 {
+        // Fuel used by executing this opcode
+        match self {
+            Opcode::MUL |
+            Opcode::IMUL |
+            Opcode::FADD |
+            Opcode::FSUB => 2,
+            Opcode::FMUL => 3,
+            Opcode::DIV |
+            Opcode::REM |
+            Opcode::IDIV |
+            Opcode::IREM |
+            Opcode::FDIV => 4,
+            // Calls into the host
+            Opcode::CAL => 10,
+            Opcode::FILE_LOAD => 100,
+            _ => 1
+        }
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -57,6 +179,12 @@ pub struct Instruction<'a> {
     pub bytes: &'a [u8]
 }
 
+impl<'a> Default for Instruction<'a> {
+    fn default() -> Instruction<'a> {
+        Instruction::new()
+    }
+}
+
 impl<'a> Instruction<'a> {
     pub fn new() -> Instruction<'a> {
         // Create arbitrary new instruction
@@ -69,62 +197,138 @@ impl<'a> Instruction<'a> {
     pub fn with_data(opcode: Opcode, bytes: &'a [u8]) -> Instruction<'a> {
         // Create instruction with data
         Instruction {
-            opcode: opcode,
-            bytes: bytes
+            opcode,
+            bytes
         }
     }
 
-    pub fn get_size(opcode: Opcode, byte: u8) -> u8 {
+    pub fn get_size(opcode: Opcode, byte: u8) -> Option<u8> {
         // Get the size of an instruction in bytes
         // Ask for opcode and following byte, as some instructions
         // may have flags set in the next byte
-        match opcode {
+        // Returns None if the byte encodes an invalid option or width
+        let hi = byte >> 4;
+        let lo = byte & 0xF;
+
+        let size = match opcode {
             Opcode::MOV_REG_REG => OPCODE + REG + REG,
             Opcode::MOV_REG_MEM | Opcode::MOV_MEM_REG => OPCODE + REG + MEM,
-            Opcode::MOV_REG_IMM => OPCODE + OPTION + REG + (byte >> 4),
-            Opcode::MOV_MEM_MEM | // Memory addresses dont always take up 32bits
-            Opcode::MOV_MEM_IMM => OPCODE + OPTION + (byte >> 4) + (byte & 0xF),
-            Opcode::SWP => OPCODE + OPTION + (byte >> 4) + (byte & 0xF),
-            Opcode::JMP_IMM => OPCODE + OPTION + (byte & 0xF),
+            Opcode::MOV_REG_IMM if hi <= IMM => OPCODE + OPTION + REG + hi,
+            Opcode::MOV_MEM_MEM if hi <= MEM && lo <= MEM => OPCODE + OPTION + hi + lo,
+            // Memory addresses dont always take up 32bits
+            Opcode::MOV_MEM_IMM if hi <= MEM && lo <= IMM => OPCODE + OPTION + hi + lo,
+            // Widths of 1 are registers, 2 or more are memory addresses
+            Opcode::SWP if (REG..=MEM).contains(&hi) && (REG..=MEM).contains(&lo) => OPCODE + OPTION + hi + lo,
+            Opcode::JMP_IMM if lo <= MEM => OPCODE + OPTION + lo,
             Opcode::JMP_REG => OPCODE + REG,
-            Opcode::JSR => OPCODE + OPTION + (byte & 0xF),
+            Opcode::JSR if lo <= MEM => OPCODE + OPTION + lo,
+            Opcode::CALL if lo <= MEM => OPCODE + OPTION + lo,
+            // The top bit selects a signed displacement in bytes rather
+            // than an absolute address
+            Opcode::JZ |
+            Opcode::JNZ |
+            Opcode::JLT |
+            Opcode::JGE |
+            Opcode::JC |
+            Opcode::JNC if lo <= MEM => OPCODE + OPTION + lo,
+            Opcode::POP => OPCODE + REG,
+            Opcode::RET => OPCODE,
             Opcode::CMP_EQ_REG_REG |
             Opcode::CMP_LE_REG_REG |
             Opcode::CMP_GE_REG_REG |
             Opcode::CMP_LT_REG_REG |
-            Opcode::CMP_GT_REG_REG => OPCODE + REG + REG,
+            Opcode::CMP_GT_REG_REG |
+            Opcode::CMP_ILE_REG_REG |
+            Opcode::CMP_IGE_REG_REG |
+            Opcode::CMP_ILT_REG_REG |
+            Opcode::CMP_IGT_REG_REG |
+            Opcode::CMP_FEQ_REG_REG |
+            Opcode::CMP_FLE_REG_REG |
+            Opcode::CMP_FGE_REG_REG |
+            Opcode::CMP_FLT_REG_REG |
+            Opcode::CMP_FGT_REG_REG => OPCODE + REG + REG,
             Opcode::CMP_EQ_REG_IMM |
             Opcode::CMP_LE_REG_IMM |
             Opcode::CMP_GE_REG_IMM |
             Opcode::CMP_LT_REG_IMM |
-            Opcode::CMP_GT_REG_IMM => OPCODE + OPTION + REG + (byte >> 4),
+            Opcode::CMP_GT_REG_IMM |
+            Opcode::CMP_ILE_REG_IMM |
+            Opcode::CMP_IGE_REG_IMM |
+            Opcode::CMP_ILT_REG_IMM |
+            Opcode::CMP_IGT_REG_IMM |
+            Opcode::CMP_FEQ_REG_IMM |
+            Opcode::CMP_FLE_REG_IMM |
+            Opcode::CMP_FGE_REG_IMM |
+            Opcode::CMP_FLT_REG_IMM |
+            Opcode::CMP_FGT_REG_IMM if hi <= IMM => OPCODE + OPTION + REG + hi,
             Opcode::AND |
+            Opcode::OR |
+            Opcode::XOR |
+            Opcode::SHL |
+            Opcode::SHR |
+            Opcode::SAR |
+            Opcode::ROL |
+            Opcode::ROR |
             Opcode::ADD |
             Opcode::SUB |
             Opcode::MUL |
             Opcode::DIV |
+            Opcode::REM |
+            Opcode::IADD |
+            Opcode::ISUB |
+            Opcode::IMUL |
+            Opcode::IDIV |
+            Opcode::IREM |
             Opcode::FADD |
             Opcode::FSUB |
             Opcode::FMUL |
             Opcode::FDIV => {
                 match byte >> 6 {
                     0b00 => OPCODE + OPTION + REG + REG + REG,
-                    0b01 => OPCODE + OPTION + REG + REG + (byte & 0xF),
-                    0b10 => OPCODE + OPTION + REG + 2 * (byte & 0xF),
-                    _ => panic!("Unexpected option passed")
+                    0b01 if lo <= IMM => OPCODE + OPTION + REG + REG + lo,
+                    0b10 if lo <= IMM => OPCODE + OPTION + REG + 2 * lo,
+                    _ => return None
                 }
             },
-            Opcode::NOT => {
+            Opcode::NOT |
+            Opcode::ITOF |
+            Opcode::FTOI => {
                 match byte >> 6 {
                     0b00 => OPCODE + OPTION  + REG + REG,
-                    0b01 => OPCODE + OPTION  + REG + (byte & 0xF),
-                    _ => panic!("Unexpected option passed")
+                    0b01 if lo <= IMM => OPCODE + OPTION  + REG + lo,
+                    _ => return None
+                }
+            },
+            Opcode::PUSH => {
+                match byte >> 6 {
+                    0b00 => OPCODE + OPTION + REG,
+                    0b01 if lo <= IMM => OPCODE + OPTION + lo,
+                    _ => return None
+                }
+            },
+            // Data register, then the byte address in a register or
+            // an immediate
+            Opcode::LOADB |
+            Opcode::LOADH |
+            Opcode::LOADW |
+            Opcode::STOREB |
+            Opcode::STOREH |
+            Opcode::STOREW => {
+                match byte >> 6 {
+                    0b00 => OPCODE + OPTION + REG + REG,
+                    0b01 if lo <= MEM => OPCODE + OPTION + REG + lo,
+                    _ => return None
                 }
             },
             Opcode::CAL => OPCODE + 1,
-            Opcode::FILE_LOAD => OPCODE + byte,
-            _ => OPCODE
-        }
+            // Status register, then the path and base addresses with
+            // widths in the high and low nibble
+            Opcode::FILE_LOAD if hi <= MEM && lo <= MEM => OPCODE + OPTION + REG + hi + lo,
+            Opcode::INVALID => OPCODE,
+            _ => return None
+        };
+
+        Some(size)
     }
 }
 
@@ -153,6 +357,7 @@ fn test_instruction_tostring() {
 }
 
 #[test]
+#[allow(clippy::unusual_byte_groupings)]
 fn test_instruction_get_size() {
     let opcodes: [Opcode; 17] = [
         Opcode::MOV_REG_REG,
@@ -196,7 +401,7 @@ fn test_instruction_get_size() {
         0b00_000000,
         0b01_000011,
         0,
-        0b00000100 // FILE_LOAD, byte encodes mem addr
+        0b0010_0100 // FILE_LOAD, byte encodes mem addr widths
     ];
 
     let expected: [u8; 17] = [
@@ -216,25 +421,42 @@ fn test_instruction_get_size() {
         OPCODE + OPTION + REG + REG, // 4
         OPCODE + OPTION + REG + 3, // 6
         OPCODE + 1, // 2
-        OPCODE + 4 // 5
+        OPCODE + OPTION + REG + 2 + 4 // 9
     ];
 
-    assert_eq!(Instruction::get_size(opcodes[0], bytes[0]), expected[0]);
-    assert_eq!(Instruction::get_size(opcodes[1], bytes[1]), expected[1]);
-    assert_eq!(Instruction::get_size(opcodes[2], bytes[2]), expected[2]);
-    assert_eq!(Instruction::get_size(opcodes[3], bytes[3]), expected[3]);
-    assert_eq!(Instruction::get_size(opcodes[4], bytes[4]), expected[4]);
-    assert_eq!(Instruction::get_size(opcodes[5], bytes[5]), expected[5]);
-    assert_eq!(Instruction::get_size(opcodes[6], bytes[6]), expected[6]);
-    assert_eq!(Instruction::get_size(opcodes[7], bytes[7]), expected[7]);
-    assert_eq!(Instruction::get_size(opcodes[8], bytes[8]), expected[8]);
-    assert_eq!(Instruction::get_size(opcodes[9], bytes[9]), expected[9]);
-    assert_eq!(Instruction::get_size(opcodes[10], bytes[10]), expected[10]);
-    assert_eq!(Instruction::get_size(opcodes[11], bytes[11]), expected[11]);
-    assert_eq!(Instruction::get_size(opcodes[12], bytes[12]), expected[12]);
-    assert_eq!(Instruction::get_size(opcodes[13], bytes[13]), expected[13]);
-    assert_eq!(Instruction::get_size(opcodes[14], bytes[14]), expected[14]);
-    assert_eq!(Instruction::get_size(opcodes[15], bytes[15]), expected[15]);
+    assert_eq!(Instruction::get_size(opcodes[0], bytes[0]), Some(expected[0]));
+    assert_eq!(Instruction::get_size(opcodes[1], bytes[1]), Some(expected[1]));
+    assert_eq!(Instruction::get_size(opcodes[2], bytes[2]), Some(expected[2]));
+    assert_eq!(Instruction::get_size(opcodes[3], bytes[3]), Some(expected[3]));
+    assert_eq!(Instruction::get_size(opcodes[4], bytes[4]), Some(expected[4]));
+    assert_eq!(Instruction::get_size(opcodes[5], bytes[5]), Some(expected[5]));
+    assert_eq!(Instruction::get_size(opcodes[6], bytes[6]), Some(expected[6]));
+    assert_eq!(Instruction::get_size(opcodes[7], bytes[7]), Some(expected[7]));
+    assert_eq!(Instruction::get_size(opcodes[8], bytes[8]), Some(expected[8]));
+    assert_eq!(Instruction::get_size(opcodes[9], bytes[9]), Some(expected[9]));
+    assert_eq!(Instruction::get_size(opcodes[10], bytes[10]), Some(expected[10]));
+    assert_eq!(Instruction::get_size(opcodes[11], bytes[11]), Some(expected[11]));
+    assert_eq!(Instruction::get_size(opcodes[12], bytes[12]), Some(expected[12]));
+    assert_eq!(Instruction::get_size(opcodes[13], bytes[13]), Some(expected[13]));
+    assert_eq!(Instruction::get_size(opcodes[14], bytes[14]), Some(expected[14]));
+    assert_eq!(Instruction::get_size(opcodes[15], bytes[15]), Some(expected[15]));
+    assert_eq!(Instruction::get_size(opcodes[16], bytes[16]), Some(expected[16]));
+}
+
+#[test]
+fn test_instruction_get_size_invalid() {
+    // Unused operand mode
+    assert_eq!(Instruction::get_size(Opcode::ADD, 0b11_000000), None);
+    assert_eq!(Instruction::get_size(Opcode::NOT, 0b10_000000), None);
+    assert_eq!(Instruction::get_size(Opcode::LOADH, 0b10_000000), None);
+
+    // Immediates wider than 8 bytes, addresses wider than 4 bytes
+    assert_eq!(Instruction::get_size(Opcode::MOV_REG_IMM, 0b1001_0000), None);
+    assert_eq!(Instruction::get_size(Opcode::MOV_MEM_IMM, 0b0101_0001), None);
+    assert_eq!(Instruction::get_size(Opcode::JMP_IMM, 0b0000_0101), None);
+    assert_eq!(Instruction::get_size(Opcode::STOREB, 0b01_000101), None);
+    assert_eq!(Instruction::get_size(Opcode::SWP, 0b0000_0001), None);
+    assert_eq!(Instruction::get_size(Opcode::SWP, 0b0001_0101), None);
 }
 
 #[test]
@@ -243,5 +465,5 @@ fn test_opcode_from_u8() {
     let invalid_opcode = Opcode::from_u8(123);
 
     assert!(valid_opcode.unwrap() == Opcode::MOV_REG_REG);
-    assert!(invalid_opcode == None);
+    assert!(invalid_opcode.is_none());
 }
\ No newline at end of file
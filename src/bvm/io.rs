@@ -0,0 +1,208 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Read, Write};
+use std::rc::Rc;
+
+// Console a guest program reads from and prints to with CAL
+pub trait VmIo {
+    // Next byte of input, None at the end of input
+    fn read_byte(&mut self) -> Option<u8>;
+    fn write(&mut self, text: &str);
+    fn write_err(&mut self, text: &str);
+
+    fn read_char(&mut self) -> Option<char> {
+        // Decode one UTF-8 character, invalid sequences read as U+FFFD
+        let first = self.read_byte()?;
+        let len = match first {
+            0xC0..=0xDF => 2,
+            0xE0..=0xEF => 3,
+            0xF0..=0xF7 => 4,
+            _ => 1
+        };
+        let mut bytes = vec![first];
+
+        while bytes.len() < len {
+            match self.read_byte() {
+                Some(byte) => bytes.push(byte),
+                None => break
+            }
+        }
+
+        let chr = std::str::from_utf8(&bytes).ok().and_then(|s| s.chars().next());
+        Some(chr.unwrap_or(char::REPLACEMENT_CHARACTER))
+    }
+
+    fn read_line(&mut self) -> Option<String> {
+        // Read up to the end of the line, without the line ending.
+        // None if the input has already ended.
+        let mut bytes: Vec<u8> = Vec::with_capacity(64);
+
+        loop {
+            match self.read_byte() {
+                Some(b'\n') => break,
+                Some(byte) => bytes.push(byte),
+                None if bytes.is_empty() => return None,
+                None => break
+            }
+        }
+
+        if bytes.last() == Some(&b'\r') {
+            bytes.pop();
+        }
+
+        Some(String::from_utf8_lossy(&bytes).into_owned())
+    }
+}
+
+// The process' stdin, stdout and stderr. Input goes through Rust's
+// stdin buffer, which the debugger shares through Unbuffered.
+pub struct Terminal;
+
+impl VmIo for Terminal {
+    fn read_byte(&mut self) -> Option<u8> {
+        let mut byte = [0];
+
+        io::stdin().read_exact(&mut byte).ok().map(|_| byte[0])
+    }
+
+    fn write(&mut self, text: &str) {
+        // Flush so prompts show up before the program waits for input
+        let mut stdout = std::io::stdout();
+
+        let _ = stdout.write_all(text.as_bytes());
+        let _ = stdout.flush();
+    }
+
+    fn write_err(&mut self, text: &str) {
+        eprint!("{}", text);
+    }
+}
+
+// In-memory console with scripted input. Clones share their buffers,
+// so a clone kept outside the VM can feed input and read the output.
+#[derive(Clone, Default)]
+pub struct Buffer {
+    input: Rc<RefCell<VecDeque<u8>>>,
+    output: Rc<RefCell<String>>,
+    error: Rc<RefCell<String>>
+}
+
+impl Buffer {
+    pub fn new(input: &str) -> Buffer {
+        let buffer = Buffer::default();
+
+        buffer.push_input(input);
+        buffer
+    }
+
+    pub fn push_input(&self, input: &str) {
+        self.input.borrow_mut().extend(input.bytes());
+    }
+
+    pub fn output(&self) -> String {
+        self.output.borrow().clone()
+    }
+
+    pub fn error(&self) -> String {
+        self.error.borrow().clone()
+    }
+}
+
+impl VmIo for Buffer {
+    fn read_byte(&mut self) -> Option<u8> {
+        self.input.borrow_mut().pop_front()
+    }
+
+    fn write(&mut self, text: &str) {
+        self.output.borrow_mut().push_str(text);
+    }
+
+    fn write_err(&mut self, text: &str) {
+        self.error.borrow_mut().push_str(text);
+    }
+}
+
+// Reads one byte at a time, so reading a line never takes input past
+// its end away from others reading the same source, such as the guest
+// and the debugger both reading stdin
+pub struct Unbuffered<R> {
+    inner: R,
+    byte: [u8; 1],
+    len: usize
+}
+
+impl<R: Read> Unbuffered<R> {
+    pub fn new(inner: R) -> Unbuffered<R> {
+        Unbuffered {
+            inner,
+            byte: [0],
+            len: 0
+        }
+    }
+}
+
+impl<R: Read> Read for Unbuffered<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.fill_buf()?.read(buf)?;
+
+        self.consume(len);
+        Ok(len)
+    }
+}
+
+impl<R: Read> BufRead for Unbuffered<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.len == 0 {
+            self.len = self.inner.read(&mut self.byte)?;
+        }
+
+        Ok(&self.byte[..self.len])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.len -= amt.min(self.len);
+    }
+}
+
+#[test]
+fn test_read() {
+    let mut io = Buffer::new("añ€\r\nline two\nlast");
+
+    assert_eq!(io.read_char(), Some('a'));
+    assert_eq!(io.read_char(), Some('ñ'));
+    assert_eq!(io.read_char(), Some('€'));
+    assert_eq!(io.read_line(), Some("".to_owned()));
+    assert_eq!(io.read_line(), Some("line two".to_owned()));
+    assert_eq!(io.read_line(), Some("last".to_owned()));
+    assert_eq!(io.read_line(), None);
+    assert_eq!(io.read_char(), None);
+
+    io.input.borrow_mut().extend(&[0xE2, 0x28]);
+    assert_eq!(io.read_char(), Some(char::REPLACEMENT_CHARACTER));
+}
+
+#[test]
+fn test_buffer() {
+    let buffer = Buffer::new("in");
+    let mut io: Box<dyn VmIo> = Box::new(buffer.clone());
+
+    io.write("hello ");
+    io.write("world");
+    io.write_err("oops");
+    buffer.push_input("put");
+
+    assert_eq!(io.read_line(), Some("input".to_owned()));
+    assert_eq!(buffer.output(), "hello world");
+    assert_eq!(buffer.error(), "oops");
+}
+
+#[test]
+fn test_unbuffered() {
+    let mut source = "step\nguest input\n".as_bytes();
+    let mut line = String::new();
+
+    Unbuffered::new(&mut source).read_line(&mut line).unwrap();
+
+    assert_eq!(line, "step\n");
+    assert_eq!(source, b"guest input\n");
+}
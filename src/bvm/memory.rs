@@ -1,71 +1,364 @@
 extern crate byteorder;
-#[path = "externals.rs"] mod externals;
 
-use std::cell::RefCell;
-use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeSet, HashMap};
+use std::ops::Range;
 use byteorder::{WriteBytesExt, BigEndian};
-use self::externals::u64_to_u8arr;
+use super::externals::u64_to_u8arr;
+use super::error::Fault;
+
+// Accesses a region may allow
+pub const READ: u8 = 1;
+pub const WRITE: u8 = 1 << 1;
+pub const EXECUTE: u8 = 1 << 2;
+
+// Storage for memory words. Addresses which were never written, or
+// were removed, are empty and read as None.
+pub trait Backend {
+    fn get(&self, addr: u32) -> Option<u64>;
+    fn set(&mut self, addr: u32, content: u64);
+    fn remove(&mut self, addr: u32);
+    // Number of addresses holding data
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Words of storage allocated together on the first write to any of
+    // them, aligned to their size
+    fn block(&self) -> u32 {
+        1
+    }
+
+    // Whether the block holding addr is allocated
+    fn holds(&self, addr: u32) -> bool {
+        self.get(addr).is_some()
+    }
+
+    // Words of storage held, which the memory limit is charged by
+    fn allocated(&self) -> usize {
+        self.len()
+    }
+}
+
+// Words in a hash map, compact for a few scattered addresses
+#[derive(Default)]
+pub struct Sparse(HashMap<u32, u64>);
+
+impl Backend for Sparse {
+    fn get(&self, addr: u32) -> Option<u64> {
+        self.0.get(&addr).copied()
+    }
+
+    fn set(&mut self, addr: u32, content: u64) {
+        self.0.insert(addr, content);
+    }
+
+    fn remove(&mut self, addr: u32) {
+        self.0.remove(&addr);
+    }
 
-pub struct Memory(RefCell<HashMap<u32, u64>>);
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+// Addresses split into a directory, a page and a word within the page
+const DIR_BITS: u32 = 10;
+const PAGE_BITS: u32 = 12;
+const PAGE_WORDS: usize = 1 << PAGE_BITS;
+
+struct Page {
+    words: Vec<u64>,
+    // One bit per word which holds data
+    present: Vec<u64>
+}
+
+type Directory = Vec<Option<Box<Page>>>;
+
+// Words in 32 KiB pages allocated on first write, found by indexing
+// instead of hashing
+pub struct Paged {
+    dirs: Vec<Option<Box<Directory>>>,
+    len: usize,
+    pages: usize
+}
+
+impl Default for Paged {
+    fn default() -> Paged {
+        Paged {
+            dirs: (0..1 << (32 - DIR_BITS - PAGE_BITS)).map(|_| None).collect(),
+            len: 0,
+            pages: 0
+        }
+    }
+}
+
+impl Paged {
+    fn page(&self, addr: u32) -> Option<&Page> {
+        let dir = self.dirs[(addr >> (DIR_BITS + PAGE_BITS)) as usize].as_ref()?;
+        dir[(addr >> PAGE_BITS) as usize & ((1 << DIR_BITS) - 1)].as_deref()
+    }
+
+    fn page_mut(&mut self, addr: u32) -> &mut Page {
+        // Page holding addr, allocating it and its directory if needed
+        let dir = self.dirs[(addr >> (DIR_BITS + PAGE_BITS)) as usize]
+            .get_or_insert_with(|| Box::new((0..1 << DIR_BITS).map(|_| None).collect()));
+
+        let pages = &mut self.pages;
+
+        dir[(addr >> PAGE_BITS) as usize & ((1 << DIR_BITS) - 1)].get_or_insert_with(|| {
+            *pages += 1;
+
+            Box::new(Page {
+                words: vec![0; PAGE_WORDS],
+                present: vec![0; PAGE_WORDS / 64]
+            })
+        })
+    }
+}
+
+impl Backend for Paged {
+    fn get(&self, addr: u32) -> Option<u64> {
+        let page = self.page(addr)?;
+        let i = addr as usize & (PAGE_WORDS - 1);
+
+        if page.present[i / 64] & 1 << (i % 64) != 0 {
+            Some(page.words[i])
+        } else {
+            None
+        }
+    }
+
+    fn set(&mut self, addr: u32, content: u64) {
+        let page = self.page_mut(addr);
+        let i = addr as usize & (PAGE_WORDS - 1);
+        let added = page.present[i / 64] & 1 << (i % 64) == 0;
+
+        page.words[i] = content;
+        page.present[i / 64] |= 1 << (i % 64);
+        self.len += added as usize;
+    }
+
+    fn remove(&mut self, addr: u32) {
+        // Pages stay allocated once written
+        if self.get(addr).is_some() {
+            let i = addr as usize & (PAGE_WORDS - 1);
+
+            self.page_mut(addr).present[i / 64] &= !(1 << (i % 64));
+            self.len -= 1;
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn block(&self) -> u32 {
+        PAGE_WORDS as u32
+    }
+
+    fn holds(&self, addr: u32) -> bool {
+        self.page(addr).is_some()
+    }
+
+    fn allocated(&self) -> usize {
+        // Directories are not counted, each covers a thousand pages
+        self.pages * PAGE_WORDS
+    }
+}
+
+struct Region {
+    words: Range<u64>,
+    access: u8
+}
+
+pub struct Memory {
+    words: RefCell<Box<dyn Backend>>,
+    // Protected regions, later ones take precedence. Addresses outside
+    // every region allow any access.
+    regions: RefCell<Vec<Region>>,
+    // Most words of storage the guest may cause to be allocated, None
+    // for no limit
+    limit: Cell<Option<usize>>
+}
+
+impl Default for Memory {
+    fn default() -> Memory {
+        Memory::new()
+    }
+}
 
 impl Memory {
     pub fn new() -> Memory {
-        Memory (
-            // Initialize with 2^16 memory locations
-            RefCell::new(HashMap::with_capacity(65536))
-        )
+        Memory::with_backend(Box::new(Paged::default()))
+    }
+
+    pub fn with_backend(backend: Box<dyn Backend>) -> Memory {
+        Memory {
+            words: RefCell::new(backend),
+            regions: RefCell::new(Vec::new()),
+            limit: Cell::new(None)
+        }
+    }
+
+    pub fn set_limit(&self, limit: Option<usize>) {
+        // Limit the words of storage the backend holds. Sparse holds a
+        // word per address filled, Paged a whole page on the first write
+        // to it. Lowering it below the usage only stops the guest from
+        // filling more.
+        self.limit.set(limit);
+    }
+
+    pub fn usage(&self) -> usize {
+        // Words of storage held by the backend
+        self.words.borrow().allocated()
+    }
+
+    pub fn available(&self) -> Option<usize> {
+        // Words of storage the guest may still cause to be allocated,
+        // None without a limit
+        self.limit.get().map(|limit| limit.saturating_sub(self.usage()))
+    }
+
+    pub fn cost(&self, start: u32, words: u32) -> usize {
+        // Words of storage filling words addresses from start allocates
+        self.cost_many(&[(start, words)])
+    }
+
+    pub fn cost_many(&self, ranges: &[(u32, u32)]) -> usize {
+        // Words of storage filling each range of a start address and a
+        // number of words allocates, counting blocks the ranges share
+        // once
+        let backend = self.words.borrow();
+        let block = backend.block();
+        let blocks: BTreeSet<u32> = ranges.iter()
+            .flat_map(|&(start, words)| (0..words).map(move |i| start.wrapping_add(i)))
+            .filter(|addr| !backend.holds(*addr))
+            .map(|addr| addr / block)
+            .collect();
+
+        blocks.len() * block as usize
+    }
+
+    fn fill(&self, start: u32, words: u32, budget: usize) -> Result<usize, Fault> {
+        // Words of storage filling words addresses from start allocates,
+        // failing with the first address which takes it over budget
+        let backend = self.words.borrow();
+        let block = backend.block();
+        let mut cost: usize = 0;
+        let mut last = None;
+
+        for i in 0..words {
+            let addr = start.wrapping_add(i);
+
+            if last != Some(addr / block) && !backend.holds(addr) {
+                cost += block as usize;
+
+                if cost > budget {
+                    return Err(Fault::QuotaExceeded(addr));
+                }
+            }
+
+            last = Some(addr / block);
+        }
+
+        Ok(cost)
+    }
+
+    pub fn try_write(&self, addr: u32, content: u64) -> Result<(), Fault> {
+        // Write for the guest, failing instead of allocating storage
+        // past the limit
+        if let Some(available) = self.available() {
+            self.fill(addr, 1, available)?;
+        }
+
+        self.write(addr, content);
+        Ok(())
+    }
+
+    pub fn try_write_bytes(&self, start: u32, bytes: &[u8]) -> Result<(), Fault> {
+        // write_bytes for the guest, writing nothing if the storage it
+        // allocates would not fit in the limit
+        if let Some(available) = self.available() {
+            self.fill(start, bytes.len().div_ceil(8) as u32, available)?;
+        }
+
+        self.write_bytes(start, bytes);
+        Ok(())
+    }
+
+    pub fn protect(&self, start: u32, len: u32, access: u8) {
+        // Allow only the READ, WRITE and EXECUTE bits of access on the
+        // len words from start
+        self.regions.borrow_mut().push(Region {
+            words: start as u64..start as u64 + len as u64,
+            access
+        });
+    }
+
+    pub fn overlaps(&self, start: u32, len: u32) -> bool {
+        // Check whether any protected region covers one of the len words
+        // from start
+        let words = start as u64..start as u64 + len as u64;
+
+        self.regions.borrow().iter()
+            .any(|region| region.words.start < words.end && words.start < region.words.end)
+    }
+
+    pub fn check(&self, addr: u32, access: u8) -> Result<(), Fault> {
+        // Check that the guest may access addr. Host reads and writes
+        // through the other methods are never checked.
+        let regions = self.regions.borrow();
+        let region = regions.iter().rev().find(|region| region.words.contains(&(addr as u64)));
+
+        match region {
+            Some(region) if region.access & access != access => Err(match access {
+                READ => Fault::NotReadable(addr),
+                WRITE => Fault::NotWritable(addr),
+                _ => Fault::NotExecutable(addr)
+            }),
+            _ => Ok(())
+        }
     }
 
     pub fn exists(&self, addr: &u32) -> bool {
         // Check to see if there exists data at this address
-        self.0.borrow().contains_key(addr)
+        self.read(*addr).is_some()
     }
 
     pub fn write(&self, addr: u32, content: u64) {
         // Write u64 content to this address
-        &self.0.borrow_mut().insert(addr, content);
+        self.words.borrow_mut().set(addr, content);
     }
 
     pub fn read(&self, addr: u32) -> Option<u64> {
-        // Read data at this address
-        if self.exists(&addr) {
-            // If it exists then return the value stored at address
-            Some(self.0.borrow()[&addr])
-        } else {
-            // Return none if not exists
-            None
-        }
+        // Read data at this address, None if there is none
+        self.words.borrow().get(addr)
     }
 
     pub fn delete(&self, addr: &u32) {
         // Deletes data at address
-        if self.exists(&addr) {
-            self.0.borrow_mut().remove_entry(addr);
-        }
+        self.words.borrow_mut().remove(*addr);
     }
 
     pub fn write_bytes(&self, start: u32, bytes: &[u8]) {
-        // Writes bytes into memory
-        let mut addr: u32 = start;
-        
-        for i in (0..bytes.len()).step_by(8) {
+        // Writes bytes into memory, up to the last address
+        for (i, chunk) in bytes.chunks(8).enumerate() {
             let mut data: u64 = 0;
 
-            for j in 0..8 {
-                if i + j < bytes.len() {
-                    data |= (bytes[i + j] as u64) << (56 - 8 * j);
-                } else {
-                    break;
-                }
+            for (j, byte) in chunk.iter().enumerate() {
+                data |= (*byte as u64) << (56 - 8 * j);
             }
 
-            self.write(addr, data);
-            addr += 1;
+            self.write(start.wrapping_add(i as u32), data);
         }
     }
 
     pub fn read_bytes_eom(&self, start: u32) -> Vec<u8> {
-        // Reads bytes into buffer until non existant or zero valued address.
+        // Reads bytes into buffer until non existant or zero valued address,
+        // or the end of memory.
         let mut addr: u32 = start;
         let mut buf: Vec<u8> = Vec::with_capacity(64);
 
@@ -81,7 +374,11 @@ impl Memory {
                     }
 
                     buf.extend_from_slice(&u64_to_u8arr(data));
-                    addr += 1;
+
+                    addr = match addr.checked_add(1) {
+                        Some(next) => next,
+                        None => break
+                    };
                 },
                 None => break
             }
@@ -91,33 +388,20 @@ impl Memory {
     }
 
     pub fn read_bytes(&self, start: u32, len: u32) -> Vec<u8> {
-        // Reads len number of bytes into buffer.
-        let mut addr: u32 = start;
-        let mut buf: Vec<u8> = Vec::with_capacity(len as usize);
-
-        'memory: loop {
-            // Read u64 from address
-            let data: Option<u64> =  self.read(addr);
-            match data {
-                Some(_) => {
-                    let data: u64 = data.unwrap();
-
-                    let bytes = u64_to_u8arr(data);
-
-                    for byte in &bytes {
-                        buf.push(*byte);
-
-                        if buf.len() == len as usize {
-                            break 'memory;
-                        }
-                    }
-
-                    addr += 1;
-                },
-                None => break 'memory
+        // Reads len number of bytes into buffer, stopping early at the
+        // first empty address.
+        let len = len as usize;
+        let mut buf: Vec<u8> = Vec::with_capacity(len.div_ceil(8) * 8);
+        let memory = self.words.borrow();
+
+        for i in 0..len.div_ceil(8) as u32 {
+            match memory.get(start.wrapping_add(i)) {
+                Some(data) => buf.extend_from_slice(&data.to_be_bytes()),
+                None => break
             }
         }
 
+        buf.truncate(len);
         buf
     }
 
@@ -138,7 +422,7 @@ impl Memory {
             chars.push(word);
         }
 
-        String::from_utf16(&chars).unwrap()
+        String::from_utf16_lossy(&chars)
     }
 
     pub fn write_utf16(&self, start: u32, string: String) {
@@ -159,7 +443,7 @@ fn test_exists() {
     let mem = Memory::new();
     assert!(!mem.exists(&0x2929));
 
-    mem.0.borrow_mut().insert(0x2929, 1);
+    mem.words.borrow_mut().set(0x2929, 1);
     assert!(mem.exists(&0x2929));
 }
 
@@ -199,6 +483,11 @@ fn test_read_bytes_eom() {
     mem.write(2, 0x0072006C00640000);
 
     assert_eq!(correct, mem.read_bytes_eom(0));
+
+    // Strings stop at the end of memory
+    mem.write(0xFFFF_FFFF, 0x0068006900210021);
+    assert_eq!(mem.read_bytes_eom(0xFFFF_FFFF).len(), 8);
+    assert_eq!(mem.read_utf16(0xFFFF_FFFF), "hi!!");
 }
 
 #[test]
@@ -250,4 +539,115 @@ fn test_write_utf16() {
     let read = mem.read_utf16(0);
 
     assert_eq!(read, "hello world".to_owned());
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_backends() {
+    let backends: Vec<Box<dyn Backend>> = vec![Box::new(Sparse::default()), Box::new(Paged::default())];
+
+    for mut backend in backends {
+        for addr in [0, 0xFFF, 0x1000, 0x3F_FFFF, u32::MAX] {
+            assert_eq!(backend.get(addr), None);
+
+            backend.set(addr, addr as u64 + 1);
+            assert_eq!(backend.get(addr), Some(addr as u64 + 1));
+        }
+
+        // Zero is a value, not an empty address
+        backend.set(0x29, 0);
+        assert_eq!(backend.get(0x29), Some(0));
+        assert_eq!(backend.get(0x2A), None);
+
+        backend.remove(0x1000);
+        backend.remove(0x5000);
+        assert_eq!(backend.get(0x1000), None);
+        assert_eq!(backend.get(0xFFF), Some(0x1000));
+
+        // Overwriting and removing twice do not change the count
+        backend.set(0, 0x29);
+        backend.remove(0x1000);
+        assert_eq!(backend.len(), 5);
+    }
+}
+
+#[test]
+fn test_protect() {
+    let mem = Memory::new();
+
+    mem.protect(0x10, 0x10, READ | EXECUTE);
+    mem.protect(0x18, 1, READ | WRITE);
+    mem.protect(0xFFFF_FFFF, 1, 0);
+
+    assert_eq!(mem.check(0x0F, WRITE), Ok(()));
+    assert_eq!(mem.check(0x10, READ), Ok(()));
+    assert_eq!(mem.check(0x10, WRITE), Err(Fault::NotWritable(0x10)));
+    assert_eq!(mem.check(0x18, WRITE), Ok(()));
+    assert_eq!(mem.check(0x18, EXECUTE), Err(Fault::NotExecutable(0x18)));
+    assert_eq!(mem.check(0x1F, EXECUTE), Ok(()));
+    assert_eq!(mem.check(0x20, WRITE), Ok(()));
+    assert_eq!(mem.check(0xFFFF_FFFF, READ), Err(Fault::NotReadable(0xFFFF_FFFF)));
+
+    assert!(mem.overlaps(0x08, 0x09));
+    assert!(!mem.overlaps(0x08, 0x08));
+    assert!(!mem.overlaps(0x20, 0x10));
+    assert!(mem.overlaps(0xFFFF_FFF0, 0x10));
+
+    // Host writes are not checked
+    mem.write(0x10, 0x29);
+    assert_eq!(mem.read(0x10), Some(0x29));
+}
+
+#[test]
+fn test_limit() {
+    let mem = Memory::with_backend(Box::new(Sparse::default()));
+
+    mem.write(0x10, 1);
+    assert_eq!((mem.usage(), mem.available()), (1, None));
+
+    mem.set_limit(Some(3));
+    assert_eq!(mem.try_write(0x11, 2), Ok(()));
+    assert_eq!(mem.available(), Some(1));
+
+    // Everything is checked before writing anything
+    assert_eq!(mem.try_write_bytes(0x20, &[0; 9]), Err(Fault::QuotaExceeded(0x21)));
+    assert_eq!(mem.read(0x20), None);
+
+    assert_eq!(mem.try_write_bytes(0x0F, &[0; 24]), Ok(()));
+    assert_eq!(mem.try_write(0x12, 3), Err(Fault::QuotaExceeded(0x12)));
+
+    // Used addresses can still be written, and freed ones reused
+    assert_eq!(mem.try_write(0x10, 4), Ok(()));
+    mem.words.borrow_mut().remove(0x0F);
+    assert_eq!(mem.try_write(0x12, 3), Ok(()));
+    assert_eq!(mem.usage(), 3);
+
+    // Host writes are not limited
+    mem.write(0x13, 5);
+    assert_eq!((mem.usage(), mem.available()), (4, Some(0)));
+}
+
+#[test]
+fn test_limit_paged() {
+    let mem = Memory::with_backend(Box::new(Paged::default()));
+
+    // The first write to a page charges all of it
+    mem.write(0x10, 1);
+    assert_eq!(mem.usage(), PAGE_WORDS);
+
+    mem.set_limit(Some(3 * PAGE_WORDS));
+    assert_eq!(mem.try_write_bytes(0x20, &[0; 0x100]), Ok(()));
+    assert_eq!(mem.usage(), PAGE_WORDS);
+    assert_eq!(mem.cost(0xFFF, 2), PAGE_WORDS);
+    assert_eq!(mem.cost_many(&[(0x1000, 1), (0x1FFF, 2), (0x20, 1)]), 2 * PAGE_WORDS);
+
+    // A fourth page does not fit, and nothing of the write is kept
+    assert_eq!(mem.try_write_bytes(0x1FFF, &[0; 0x10]), Ok(()));
+    assert_eq!(mem.try_write_bytes(0x2FFF, &[0; 0x10]), Err(Fault::QuotaExceeded(0x3000)));
+    assert_eq!(mem.try_write(0x3000, 1), Err(Fault::QuotaExceeded(0x3000)));
+    assert_eq!(mem.read(0x2FFF), None);
+
+    // Removing words does not free their page
+    mem.words.borrow_mut().remove(0x1FFF);
+    assert_eq!((mem.usage(), mem.available()), (3 * PAGE_WORDS, Some(0)));
+    assert_eq!(mem.try_write(0x1FFF, 2), Ok(()));
+}
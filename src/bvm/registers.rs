@@ -1,33 +1,50 @@
-use std::cell::RefCell;
-use std::collections::HashMap;
+use std::cell::Cell;
 
-pub struct Registers(RefCell<HashMap<u8, u64>>);
+// Every register in an array, with a bit for each one which was set
+pub struct Registers {
+    values: [Cell<u64>; 256],
+    used: [Cell<u64>; 4]
+}
+
+impl Default for Registers {
+    fn default() -> Registers {
+        Registers::new()
+    }
+}
 
 impl Registers {
     pub fn new() -> Registers {
-        Registers(
-            // Initialize with space for 32 registers
-            RefCell::new(HashMap::with_capacity(32))
-        )
+        Registers {
+            values: std::array::from_fn(|_| Cell::new(0)),
+            used: Default::default()
+        }
     }
 
     pub fn exists(&self, register: &u8) -> bool {
-        // Check to see if register exists
-        self.0.borrow().contains_key(register)
+        // Check to see if register has been set
+        let register = *register as usize;
+        self.used[register / 64].get() & 1 << (register % 64) != 0
     }
 
     pub fn get(&self, register: &u8) -> u64 {
-        // Get the value stored in register
-        if self.exists(register) {
-            self.0.borrow()[register]
-        } else {
-            0
-        }
+        // Get the value stored in register, 0 if it was never set
+        self.values[*register as usize].get()
     }
 
     pub fn set(&self, register: u8, data: u64) {
         // Set the value of a register
-        &self.0.borrow_mut().insert(register, data);
+        let used = &self.used[register as usize / 64];
+
+        used.set(used.get() | 1 << (register % 64));
+        self.values[register as usize].set(data);
+    }
+
+    pub fn list(&self) -> Vec<(u8, u64)> {
+        // All registers which have been set, in order
+        (0..=u8::MAX)
+            .filter(|register| self.exists(register))
+            .map(|register| (register, self.get(&register)))
+            .collect()
     }
 }
 
@@ -36,7 +53,7 @@ fn test_exists() {
     let reg = Registers::new();
     assert!(!reg.exists(&29));
 
-    reg.0.borrow_mut().insert(29, 29);
+    reg.set(29, 29);
     assert!(reg.exists(&29));
 }
 
@@ -46,6 +63,16 @@ fn test_get() {
 
     assert_eq!(reg.get(&29), 0);
 
-    reg.0.borrow_mut().insert(29, 29);
+    reg.set(29, 29);
     assert_eq!(reg.get(&29), 29);
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_list() {
+    let reg = Registers::new();
+
+    reg.set(29, 2929);
+    reg.set(1, 1);
+
+    assert_eq!(reg.list(), vec![(1, 1), (29, 2929)]);
+}
@@ -10,100 +10,516 @@ pub mod memory;
 #[path = "registers.rs"]
 pub mod registers;
 
+#[path = "error.rs"]
+pub mod error;
+
+#[path = "container.rs"]
+pub mod container;
+
+#[path = "io.rs"]
+pub mod io;
+
 use registers::Registers;
-use memory::Memory;
-use instructions::{Instruction, Opcode};
-use externals::{u64_to_u8arr, u8arr_to_u32, u8arr_to_u64};
+use memory::{Memory, READ, WRITE, EXECUTE};
+use instructions::{Instruction, Opcode, MAX_SIZE};
+use error::{CallError, Fault, VmError};
+use container::{Container, LoadError, SectionKind};
+use io::{VmIo, Terminal};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+use externals::{u64_to_u8arr, u8arr_to_u32, u8arr_to_u64, u8arr_to_i64};
+
+// Register holding the exit code when CAL HLT is executed
+const EXIT_REGISTER: u8 = 0;
+
+// Register holding the word address of the top of the stack
+pub const STACK_POINTER: u8 = 254;
+
+// Bits of the flags register
+pub const FLAG_ZERO: u8 = 1;
+pub const FLAG_NEGATIVE: u8 = 1 << 1;
+pub const FLAG_CARRY: u8 = 1 << 2;
+pub const FLAG_OVERFLOW: u8 = 1 << 3;
+
+// Calls built into CAL, by basm name
+pub const CALLS: &[(&str, u8)] = &[
+    ("getc", 0x90),
+    ("getl", 0x91),
+    ("geti", 0x92),
+    ("pnt", 0x9A),
+    ("pnti", 0x9B),
+    ("pntf", 0x9C),
+    ("hlt", 0x9D),
+    ("pntc", 0x9E),
+    ("epnt", 0x9F)
+];
+
+// Function an embedder exposes to guest code through CAL
+pub type HostFn = Box<dyn FnMut(&Registers, &Memory) -> Result<(), Fault>>;
+
+struct Host {
+    name: String,
+    call: HostFn
+}
+
+// Status left in FILE_LOAD's register
+pub const LOAD_OK: u64 = 0;
+// No module directory is configured, the path leaves it, or the module
+// would overlap sections already loaded
+pub const LOAD_DENIED: u64 = 1;
+// The file does not exist or cannot be read
+pub const LOAD_UNREADABLE: u64 = 2;
+// The file is not valid bytecode, does not fit at the address or would
+// go over the memory limit
+pub const LOAD_INVALID: u64 = 3;
+
+// Default stack region, growing down from the end
+const STACK_END: u32 = 0xFFFF_0000;
+const STACK_SIZE: u32 = 0x1_0000;
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum ExitReason {
+    // CAL HLT was executed, with the exit code in EXIT_REGISTER
+    Halted(u64),
+    // run_until reached the instruction at this byte address
+    Breakpoint(u64),
+    // The next instruction costs more fuel than is left, refuel and
+    // resume to continue
+    OutOfFuel
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Step {
+    // The instruction was executed
+    Executed(Decoded),
+    // The next instruction costs more fuel than is left, nothing was
+    // executed
+    OutOfFuel
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Decoded {
+    // Word address and byte offset of the opcode
+    pub addr: u32,
+    pub offset: usize,
+    pub opcode: Opcode,
+    // Instruction bytes, held inline so fetching does not allocate
+    bytes: [u8; MAX_SIZE],
+    size: usize
+}
+
+impl Decoded {
+    pub fn read(mem: &Memory, addr: u32, offset: usize) -> Result<Decoded, VmError> {
+        // Decode the first instruction at or after addr and offset.
+        // Bytes which are not opcodes are padding and are skipped, and
+        // a zero byte pads out the rest of its word, so data left after
+        // the padding is never mistaken for an instruction.
+        Decoded::find(mem, addr, offset, |_| Ok(()))
+    }
+
+    fn find<F>(mem: &Memory, addr: u32, offset: usize, check: F) -> Result<Decoded, VmError>
+    where
+        F: Fn(u32) -> Result<(), Fault>
+    {
+        // read, calling check on each word before looking in it
+        let mut addr = addr;
+        let mut offset = offset;
+
+        loop {
+            check(addr).map_err(|cause| VmError::new(addr, None, cause))?;
+
+            // Programs must stop with CAL HLT rather than running
+            // off into empty memory
+            let word = mem.read(addr)
+                .ok_or(VmError::new(addr, None, Fault::EndOfProgram))?;
+            let bytes: [u8; 8] = u64_to_u8arr(word);
+
+            while offset < 8 && bytes[offset] != 0 {
+                if let Some(op) = Opcode::from_u8(bytes[offset]) {
+                    return Decoded::decode(mem, addr, offset, op, bytes);
+                }
+
+                offset += 1;
+            }
+
+            addr = addr.wrapping_add(1);
+            offset = 0;
+        }
+    }
+
+    fn decode(mem: &Memory, addr: u32, offset: usize, op: Opcode, first: [u8; 8]) -> Result<Decoded, VmError> {
+        // Read the bytes of the instruction starting at offset in the
+        // word first, read from addr, which may continue into the
+        // following words
+        let fault = |cause| VmError::new(addr, Some(op), cause);
+        // Starting in the last byte of a word, the longest instruction
+        // spans four words
+        let mut words = [0; 32];
+        let mut read = 1;
+
+        words[..8].copy_from_slice(&first);
+
+        let option = if offset < 7 {
+            first[offset + 1]
+        } else {
+            let next = addr.wrapping_add(1);
+
+            match mem.read(next) {
+                Some(word) => {
+                    words[8..16].copy_from_slice(&word.to_be_bytes());
+                    read = 2;
+                    words[8]
+                },
+                // Single byte instructions may sit in the last byte of
+                // the last word
+                None if Instruction::get_size(op, 0) == Some(1) => 0,
+                None => return Err(fault(Fault::InvalidAddress(next)))
+            }
+        };
+        let size = Instruction::get_size(op, option)
+            .ok_or(Fault::InvalidOption(option))
+            .map_err(fault)? as usize;
+
+        for i in read..(offset + size).div_ceil(8) {
+            // The instruction runs into an empty address
+            let next = addr.wrapping_add(i as u32);
+            let word = mem.read(next)
+                .ok_or(Fault::InvalidAddress(next))
+                .map_err(fault)?;
+
+            words[i * 8..i * 8 + 8].copy_from_slice(&word.to_be_bytes());
+        }
+
+        let mut bytes = [0; MAX_SIZE];
+        bytes[..size].copy_from_slice(&words[offset..offset + size]);
+
+        Ok(Decoded {
+            addr,
+            offset,
+            opcode: op,
+            bytes,
+            size
+        })
+    }
+
+    pub fn pc(&self) -> u64 {
+        // Byte address of the opcode
+        self.addr as u64 * 8 + self.offset as u64
+    }
+
+    pub fn end(&self) -> (u32, usize) {
+        // Word address and byte offset just past this instruction
+        let end = self.offset + self.size;
 
-// If there are no instrucions for this long, then halt.
-const TIMEOUT: u32 = 128;
+        (self.addr.wrapping_add((end / 8) as u32), end % 8)
+    }
+
+    pub fn instruction(&self) -> Instruction<'_> {
+        Instruction::with_data(self.opcode, &self.bytes[..self.size])
+    }
+}
 
 pub struct VM {
     pub mem: Memory,
     pub reg: Registers,
+    // Word address and byte offset of the next instruction
     pub addr: u32,
-    pub running: bool
+    pub offset: usize,
+    // Word address run starts from, set by load
+    pub entry: u32,
+    pub running: bool,
+    // Remaining fuel, None if execution is not metered
+    pub fuel: Option<u64>,
+    // Words the stack may use, pushing moves the stack pointer down
+    // from the end of the range
+    pub stack: Range<u32>,
+    // FLAG_* bits set by the last arithmetic or CMP instruction
+    pub flags: Cell<u8>,
+    // Directory FILE_LOAD may read modules from, None disables it
+    pub modules: Option<PathBuf>,
+    // Console used by the CAL input and print calls
+    pub io: Box<dyn VmIo>,
+    // Host functions registered for CAL, by call number
+    hosts: HashMap<u8, Host>
+}
+
+impl Default for VM {
+    fn default() -> VM {
+        VM::new()
+    }
 }
 
 impl VM {
     pub fn new() -> VM {
-        VM {
+        VM::with_io(Box::new(Terminal))
+    }
+
+    pub fn with_io(io: Box<dyn VmIo>) -> VM {
+        // Create a VM whose CAL input and print calls use io
+        let vm = VM {
             mem: Memory::new(),
             reg: Registers::new(),
             addr: 0,
-            running: false
+            offset: 0,
+            entry: 0,
+            running: false,
+            fuel: None,
+            stack: STACK_END - STACK_SIZE..STACK_END,
+            flags: Cell::new(0),
+            modules: None,
+            io,
+            hosts: HashMap::new()
+        };
+
+        vm.reg.set(STACK_POINTER, vm.stack.end as u64);
+        vm
+    }
+
+    pub fn register<F>(&mut self, code: u8, name: &str, call: F) -> Result<(), CallError>
+    where
+        F: FnMut(&Registers, &Memory) -> Result<(), Fault> + 'static
+    {
+        // Run call for CAL code, which basm accepts by name once given
+        // the table from calls. Built in calls and codes held by another
+        // name cannot be replaced; registering a name again moves it.
+        let name = name.to_lowercase();
+
+        if CALLS.iter().any(|(n, c)| *c == code || *n == name) {
+            return Err(CallError::BuiltIn(name, code));
+        }
+
+        if let Some(host) = self.hosts.get(&code) {
+            if host.name != name {
+                return Err(CallError::Taken(host.name.clone(), code));
+            }
         }
+
+        self.hosts.retain(|_, host| host.name != name);
+        self.hosts.insert(code, Host { name, call: Box::new(call) });
+        Ok(())
     }
 
-    pub fn run(&mut self) {
-        self.addr = 0;
-        self.running = true;
-        let mut nop = 0;
-        let mut skip_bytes = 0;
+    pub fn calls(&self) -> Vec<(String, u8)> {
+        // Names and numbers of the built in and registered calls
+        let mut calls: Vec<(String, u8)> = CALLS.iter()
+            .map(|(name, code)| (name.to_string(), *code))
+            .chain(self.hosts.iter().map(|(code, host)| (host.name.clone(), *code)))
+            .collect();
 
-        loop {
-            let memory = self.mem.read(self.addr);
-
-            match memory {
-                None => nop += 1,
-                Some(_) => {
-                    nop = 0;
-                    let bytes: [u8; 8] = u64_to_u8arr(memory.unwrap());
-
-                    for i in 0..8 {
-                        if skip_bytes != 0 {
-                            skip_bytes -= 1;
-                            continue;
-                        }
-                        let op = Opcode::from_u8(bytes[i]);
-
-                        if op != None {
-                            let op = op.unwrap();
-                            let size = Instruction::get_size(op, self.next_byte(i)) as usize;
-                            let rbytes = self.mem.read_bytes(self.addr, (i + size) as u32);
-                            let mut bytes: Vec<u8> = Vec::new();
-                            bytes.extend_from_slice(&rbytes[i..i + size]);
-
-                            if i == 7 {
-                                bytes.extend_from_slice(&[self.next_byte(i)]);
-                            }
-
-                            skip_bytes += size - 1;
-
-                            self.execute(Instruction::with_data(op, &bytes));
-                        }
-                    }
-                }
+        calls.sort_by_key(|(_, code)| *code);
+        calls
+    }
+
+    pub fn refuel(&mut self, fuel: u64) {
+        // Add fuel, metering execution from now on if it was not already
+        self.fuel = Some(self.fuel.unwrap_or(0).saturating_add(fuel));
+    }
+
+    pub fn load(&mut self, container: &Container) -> Result<(), LoadError> {
+        // Validate the container, then write its sections to memory
+        // and move to the entry point
+        self.load_sections(container)?;
+
+        self.entry = container.entry;
+        self.addr = container.entry;
+        self.offset = 0;
+
+        Ok(())
+    }
+
+    fn load_sections(&mut self, container: &Container) -> Result<(), LoadError> {
+        // Validate the container and write its sections to memory.
+        // Code is read-only and data cannot be executed.
+        container.validate()?;
+
+        for section in &container.sections {
+            let access = match section.kind {
+                SectionKind::Code => READ | EXECUTE,
+                SectionKind::Data => READ | WRITE
+            };
+
+            self.mem.write_bytes(section.load, &section.bytes);
+            self.mem.protect(section.load, section.words(), access);
+        }
+
+        Ok(())
+    }
+
+    pub fn load_module(&mut self, path: &str, base: u32) -> Result<(), u64> {
+        // Load the bytecode file at path, relative to the module
+        // directory, with its sections moved up by base words. Errors
+        // are the LOAD_* status codes.
+        let dir = self.modules.as_ref()
+            .and_then(|dir| dir.canonicalize().ok())
+            .ok_or(LOAD_DENIED)?;
+        // Paths leaving the directory are refused before touching the
+        // file system, so whether a file outside it exists stays hidden
+        let mut relative = PathBuf::new();
+
+        for component in Path::new(path).components() {
+            match component {
+                Component::Normal(name) => relative.push(name),
+                Component::CurDir => {},
+                Component::ParentDir if relative.pop() => {},
+                _ => return Err(LOAD_DENIED)
             }
+        }
+
+        let file = dir.join(relative).canonicalize().map_err(|_| LOAD_UNREADABLE)?;
+
+        // Symlinks inside the directory may still point out of it
+        if !file.starts_with(&dir) {
+            return Err(LOAD_DENIED);
+        }
+
+        let bytes = std::fs::read(&file).map_err(|_| LOAD_UNREADABLE)?;
+        let mut container = Container::read(&bytes).map_err(|_| LOAD_INVALID)?;
+
+        container.relocate(base).map_err(|_| LOAD_INVALID)?;
+
+        // Loading over loaded sections could replace read-only code
+        if container.sections.iter().any(|section| self.mem.overlaps(section.load, section.words())) {
+            return Err(LOAD_DENIED);
+        }
+
+        let ranges: Vec<(u32, u32)> = container.sections.iter()
+            .map(|section| (section.load, section.words()))
+            .collect();
+        let cost = self.mem.cost_many(&ranges);
+
+        if self.mem.available().is_some_and(|available| cost > available) {
+            return Err(LOAD_INVALID);
+        }
 
-            if nop >= TIMEOUT {
-                break;
+        self.load_sections(&container).map_err(|_| LOAD_INVALID)
+    }
+
+    pub fn run(&mut self) -> Result<ExitReason, VmError> {
+        // Run the program from the entry point until it halts, with
+        // an empty stack
+        self.addr = self.entry;
+        self.offset = 0;
+        self.reg.set(STACK_POINTER, self.stack.end as u64);
+
+        self.resume()
+    }
+
+    pub fn resume(&mut self) -> Result<ExitReason, VmError> {
+        // Continue from the current address until the program halts
+        // or runs out of fuel
+        loop {
+            let decoded = self.fetch()?;
+
+            if !self.consume(decoded.opcode) {
+                return Ok(ExitReason::OutOfFuel);
             }
 
-            self.addr += 1;
+            self.dispatch(&decoded)?;
+
+            if !self.running {
+                return Ok(ExitReason::Halted(self.reg.get(&EXIT_REGISTER)));
+            }
         }
     }
 
-    fn next_byte(&self, i: usize) -> u8 {
-        // Get the next byte in memory starting from byte index i
-        let i = i + 1;
+    pub fn run_until(&mut self, pc: u64) -> Result<ExitReason, VmError> {
+        // Continue until the program halts, or the next instruction
+        // to execute starts at the byte address pc. The first
+        // instruction always runs, so calling this again from a
+        // breakpoint moves on to the next time pc is reached.
+        let mut first = true;
 
-        if i < 7 {
-            let memory = self.mem.read(self.addr);
+        loop {
+            let decoded = self.fetch()?;
 
-            u64_to_u8arr(memory.unwrap())[i]
-        } else {
-            let memory = self.mem.read(self.addr + 1);
+            if !first && decoded.pc() == pc {
+                return Ok(ExitReason::Breakpoint(pc));
+            }
+
+            first = false;
+
+            if !self.consume(decoded.opcode) {
+                return Ok(ExitReason::OutOfFuel);
+            }
+
+            self.dispatch(&decoded)?;
 
-            if memory != None {
-                (memory.unwrap() >> 56) as u8
-            } else {
-                panic!("Unexpected empty address {:#010X}", self.addr + 1);
+            if !self.running {
+                return Ok(ExitReason::Halted(self.reg.get(&EXIT_REGISTER)));
             }
         }
     }
 
-    fn execute(&mut self, inst: Instruction) {
+    pub fn step(&mut self) -> Result<Step, VmError> {
+        // Execute exactly one instruction and return it.
+        // running is cleared if the instruction was CAL HLT.
+        let decoded = self.fetch()?;
+
+        if !self.consume(decoded.opcode) {
+            return Ok(Step::OutOfFuel);
+        }
+
+        self.dispatch(&decoded)?;
+
+        Ok(Step::Executed(decoded))
+    }
+
+    pub fn fetch(&self) -> Result<Decoded, VmError> {
+        // Decode the next instruction without executing it. Each word
+        // it occupies, and each word of padding skipped to reach it,
+        // must be executable.
+        let decoded = Decoded::find(&self.mem, self.addr, self.offset, |addr| self.mem.check(addr, EXECUTE))?;
+        let (end, offset) = decoded.end();
+        let last = if offset == 0 { end.wrapping_sub(1) } else { end };
+
+        for i in 1..=last.wrapping_sub(decoded.addr) {
+            self.mem.check(decoded.addr.wrapping_add(i), EXECUTE)
+                .map_err(|cause| VmError::new(decoded.addr, Some(decoded.opcode), cause))?;
+        }
+
+        Ok(decoded)
+    }
+
+    fn consume(&mut self, opcode: Opcode) -> bool {
+        // Take the cost of opcode from the remaining fuel, returns false
+        // without taking anything if there is not enough
+        match self.fuel {
+            None => true,
+            Some(fuel) if fuel >= opcode.cost() => {
+                self.fuel = Some(fuel - opcode.cost());
+                true
+            },
+            Some(_) => false
+        }
+    }
+
+    fn dispatch(&mut self, decoded: &Decoded) -> Result<(), VmError> {
+        // Move past the instruction, then execute it. Jumps and
+        // comparisons may move the address again.
+        let (addr, offset) = decoded.end();
+        self.addr = addr;
+        self.offset = offset;
+        self.running = true;
+
+        self.execute(decoded.instruction())
+            .map_err(|cause| VmError::new(decoded.addr, Some(decoded.opcode), cause))
+    }
+
+    pub fn pc(&self) -> u64 {
+        // Byte address of the next instruction. Jump targets, return
+        // addresses and displacements are all in bytes.
+        self.addr as u64 * 8 + self.offset as u64
+    }
+
+    pub fn jump(&mut self, pc: u64) {
+        // Continue from the byte address pc
+        self.addr = (pc / 8) as u32;
+        self.offset = (pc % 8) as usize;
+    }
+
+    fn execute(&mut self, inst: Instruction) -> Result<(), Fault> {
         match inst.opcode {
             Opcode::MOV_REG_REG |
             Opcode::MOV_REG_MEM |
@@ -115,6 +531,16 @@ impl VM {
             Opcode::JMP_IMM |
             Opcode::JMP_REG |
             Opcode::JSR => self.execute_jump(inst),
+            Opcode::PUSH |
+            Opcode::POP |
+            Opcode::CALL |
+            Opcode::RET => self.execute_stack(inst),
+            Opcode::JZ |
+            Opcode::JNZ |
+            Opcode::JLT |
+            Opcode::JGE |
+            Opcode::JC |
+            Opcode::JNC => self.execute_branch(inst),
             Opcode::CMP_EQ_REG_REG |
             Opcode::CMP_LE_REG_REG |
             Opcode::CMP_GE_REG_REG |
@@ -124,26 +550,63 @@ impl VM {
             Opcode::CMP_LE_REG_IMM |
             Opcode::CMP_GE_REG_IMM |
             Opcode::CMP_LT_REG_IMM |
-            Opcode::CMP_GT_REG_IMM => self.execute_comparison(inst),
-            // TODO: we should use signed integers rather than unsigned
-            // TODO: add sign extend function to support this the above
+            Opcode::CMP_GT_REG_IMM |
+            Opcode::CMP_ILE_REG_REG |
+            Opcode::CMP_IGE_REG_REG |
+            Opcode::CMP_ILT_REG_REG |
+            Opcode::CMP_IGT_REG_REG |
+            Opcode::CMP_ILE_REG_IMM |
+            Opcode::CMP_IGE_REG_IMM |
+            Opcode::CMP_ILT_REG_IMM |
+            Opcode::CMP_IGT_REG_IMM |
+            Opcode::CMP_FEQ_REG_REG |
+            Opcode::CMP_FLE_REG_REG |
+            Opcode::CMP_FGE_REG_REG |
+            Opcode::CMP_FLT_REG_REG |
+            Opcode::CMP_FGT_REG_REG |
+            Opcode::CMP_FEQ_REG_IMM |
+            Opcode::CMP_FLE_REG_IMM |
+            Opcode::CMP_FGE_REG_IMM |
+            Opcode::CMP_FLT_REG_IMM |
+            Opcode::CMP_FGT_REG_IMM => self.execute_comparison(inst),
             Opcode::AND |
+            Opcode::OR |
+            Opcode::XOR |
+            Opcode::SHL |
+            Opcode::SHR |
+            Opcode::SAR |
+            Opcode::ROL |
+            Opcode::ROR => self.execute_bitwise(inst),
             Opcode::ADD |
             Opcode::SUB |
             Opcode::MUL |
-            Opcode::DIV => self.execute_arithmetic(inst),
+            Opcode::DIV |
+            Opcode::REM => self.execute_arithmetic(inst),
+            Opcode::IADD |
+            Opcode::ISUB |
+            Opcode::IMUL |
+            Opcode::IDIV |
+            Opcode::IREM => self.execute_signed_arithmetic(inst),
             Opcode::FADD |
             Opcode::FSUB |
             Opcode::FMUL |
             Opcode::FDIV => self.execute_fp_arithmetic(inst),
             Opcode::NOT => self.execute_not(inst),
+            Opcode::ITOF |
+            Opcode::FTOI => self.execute_conversion(inst),
             Opcode::CAL => self.execute_call(inst),
-            Opcode::FILE_LOAD => {},
-            _ => {}
+            Opcode::FILE_LOAD => self.execute_file_load(inst),
+            Opcode::LOADB |
+            Opcode::LOADH |
+            Opcode::LOADW |
+            Opcode::STOREB |
+            Opcode::STOREH |
+            Opcode::STOREW => self.execute_load_store(inst),
+            _ => Ok(())
         }
     }
 
-    fn execute_mov(&self, inst: Instruction) {
+    fn execute_mov(&self, inst: Instruction) -> Result<(), Fault> {
         match inst.opcode {
             Opcode::MOV_REG_REG => {
                 let dst = inst.bytes[1];
@@ -155,28 +618,20 @@ impl VM {
                 let dst = inst.bytes[1];
                 let src = u8arr_to_u32(&inst.bytes[2..=5]);
 
-                if self.mem.exists(&src) {
-                    self.reg.set(dst, self.mem.read(src).unwrap());
-                } else {
-                    panic!("Memory address, {:#010X}, does not exist!", src);
-                }
+                self.reg.set(dst, self.read_word(src)?);
             },
             Opcode::MOV_MEM_REG => {
                 let dst = u8arr_to_u32(&inst.bytes[1..=4]);
                 let src = inst.bytes[5];
 
-                self.mem.write(dst, self.reg.get(&src));
+                self.write_word(dst, self.reg.get(&src))?;
             },
             Opcode::MOV_MEM_MEM => {
                 let d = 2 + (inst.bytes[1] >> 4) as usize;
                 let dst = u8arr_to_u32(&inst.bytes[2..d]);
                 let src = u8arr_to_u32(&inst.bytes[d..]);
 
-                if self.mem.exists(&src) {
-                    self.mem.write(dst, self.mem.read(src).unwrap());
-                } else {
-                    panic!("Memory address, {:#010X}, does not exist!", src);
-                }
+                self.write_word(dst, self.read_word(src)?)?;
             },
             Opcode::MOV_REG_IMM => {
                 let dst = inst.bytes[2];
@@ -189,158 +644,598 @@ impl VM {
                 let dst = u8arr_to_u32(&inst.bytes[2..d]);
                 let src = u8arr_to_u64(&inst.bytes[d..]);
 
-                self.mem.write(dst, src);
+                self.write_word(dst, src)?;
+            },
+            Opcode::SWP => {
+                // Both operands are read before either is written, so a
+                // fault leaves registers and memory untouched
+                let d = 2 + (inst.bytes[1] >> 4) as usize;
+                let lhs = &inst.bytes[2..d];
+                let rhs = &inst.bytes[d..];
+
+                let lhs_data = self.read_operand(lhs)?;
+                let rhs_data = self.read_operand(rhs)?;
+
+                for operand in [lhs, rhs] {
+                    if operand.len() > 1 {
+                        self.mem.check(u8arr_to_u32(operand), WRITE)?;
+                    }
+                }
+
+                self.write_operand(lhs, rhs_data);
+                self.write_operand(rhs, lhs_data);
             },
-            _ => panic!("Non mov instruction found.")
+            _ => return Err(Fault::InvalidOption(inst.bytes[1]))
         }
+
+        Ok(())
+    }
+
+    fn read_operand(&self, operand: &[u8]) -> Result<u64, Fault> {
+        // Operands one byte wide are registers, wider ones are addresses
+        if operand.len() == 1 {
+            return Ok(self.reg.get(&operand[0]));
+        }
+
+        self.read_word(u8arr_to_u32(operand))
+    }
+
+    fn write_operand(&self, operand: &[u8], data: u64) {
+        if operand.len() == 1 {
+            self.reg.set(operand[0], data);
+        } else {
+            self.mem.write(u8arr_to_u32(operand), data);
+        }
+    }
+
+    fn read_word(&self, addr: u32) -> Result<u64, Fault> {
+        // Read a word for the guest, which needs read access
+        self.mem.check(addr, READ)?;
+        self.mem.read(addr).ok_or(Fault::InvalidAddress(addr))
+    }
+
+    fn write_word(&self, addr: u32, data: u64) -> Result<(), Fault> {
+        // Write a word for the guest, which needs write access and room
+        // under the memory limit
+        self.mem.check(addr, WRITE)?;
+        self.mem.try_write(addr, data)
+    }
+
+    fn read_bytes(&self, addr: u64, len: usize) -> Result<u64, Fault> {
+        // Read len bytes at a byte address for the guest, big endian
+        // like the bytes within a word. Word addresses wrap around.
+        let word = (addr >> 3) as u32;
+        let offset = (addr & 7) as usize;
+        let mut bytes = [0; 16];
+
+        for i in 0..(offset + len).div_ceil(8) {
+            let data = self.read_word(word.wrapping_add(i as u32))?;
+            bytes[i * 8..i * 8 + 8].copy_from_slice(&data.to_be_bytes());
+        }
+
+        let mut data = [0; 8];
+        data[8 - len..].copy_from_slice(&bytes[offset..offset + len]);
+
+        Ok(u64::from_be_bytes(data))
+    }
+
+    fn write_bytes(&self, addr: u64, len: usize, data: u64) -> Result<(), Fault> {
+        // Write the low len bytes of data at a byte address for the
+        // guest, keeping the rest of the words. Nothing is written if
+        // any word is not writable or would go over the memory limit.
+        let word = (addr >> 3) as u32;
+        let offset = (addr & 7) as usize;
+        let words = (offset + len).div_ceil(8);
+        let mut bytes = [0; 16];
+
+        for i in 0..words {
+            let addr = word.wrapping_add(i as u32);
+
+            self.mem.check(addr, WRITE)?;
+            bytes[i * 8..i * 8 + 8].copy_from_slice(&self.mem.read(addr).unwrap_or(0).to_be_bytes());
+        }
+
+        bytes[offset..offset + len].copy_from_slice(&data.to_be_bytes()[8 - len..]);
+        self.mem.try_write_bytes(word, &bytes[..words * 8])
+    }
+
+    fn read_string(&self, addr: u32) -> Result<String, Fault> {
+        // Read a string for the guest, which needs read access to every
+        // word up to the one holding its terminator
+        let string = self.mem.read_utf16(addr);
+        let words = (string.encode_utf16().count() / 4) as u32;
+
+        for i in 0..=words {
+            // Reading stops at the end of memory
+            if let Some(addr) = addr.checked_add(i) {
+                self.mem.check(addr, READ)?;
+            }
+        }
+
+        Ok(string)
     }
 
-    fn execute_jump(&mut self, inst: Instruction) {
+    fn execute_jump(&mut self, inst: Instruction) -> Result<(), Fault> {
         match inst.opcode {
             Opcode::JMP_IMM |
             Opcode::JSR => {
                 let addr = u8arr_to_u32(&inst.bytes[2..]);
 
                 if inst.opcode == Opcode::JSR {
-                    // Store incremented address in register 255
-                    // upon RET (JMP R255), jump back to this stored addr.
-                    self.reg.set(255, (self.addr + 1) as u64);
+                    // Store the address of the next instruction in
+                    // register 255, JMP R255 jumps back to it.
+                    self.reg.set(255, self.pc());
                 }
 
-                // Because addr is incremented after execution,
-                // we must subtract.
-                // NOTE: maybe fix this?
-                self.addr = addr - 1;
-            },
-            Opcode::JMP_REG => {
-                let addr = self.reg.get(&inst.bytes[1]) as u32;
-                self.addr = addr - 1; // see above as to why we subtract
+                self.jump(addr as u64);
             },
-            _ => panic!("Non jmp instruction found.")
+            Opcode::JMP_REG => self.jump(self.reg.get(&inst.bytes[1])),
+            _ => {}
         }
+
+        Ok(())
     }
 
-    fn execute_comparison(&mut self, inst: Instruction) {
-        match inst.opcode {
-            // we increment addr by 1 instead of 2 because the address is
-            // incremented again after the execution of this function
-            // NOTE: fix this?
-            Opcode::CMP_EQ_REG_REG =>
-                if !(self.reg.get(&inst.bytes[1]) == self.reg.get(&inst.bytes[2])) {self.addr += 1; },
-            Opcode::CMP_LE_REG_REG =>
-                if !(self.reg.get(&inst.bytes[1]) <= self.reg.get(&inst.bytes[2])) {self.addr += 1; },
-            Opcode::CMP_GE_REG_REG =>
-                if !(self.reg.get(&inst.bytes[1]) >= self.reg.get(&inst.bytes[2])) {self.addr += 1; },
-            Opcode::CMP_LT_REG_REG =>
-                if !(self.reg.get(&inst.bytes[1]) < self.reg.get(&inst.bytes[2])) {self.addr += 1; },
-            Opcode::CMP_GT_REG_REG =>
-                if !(self.reg.get(&inst.bytes[1]) > self.reg.get(&inst.bytes[2])) {self.addr += 1; },
-            Opcode::CMP_EQ_REG_IMM =>
-                if !(self.reg.get(&inst.bytes[2]) == u8arr_to_u64(&inst.bytes[3..])) { self.addr += 1 },
-            Opcode::CMP_LE_REG_IMM =>
-                if !(self.reg.get(&inst.bytes[2]) <= u8arr_to_u64(&inst.bytes[3..])) { self.addr += 1 },
-            Opcode::CMP_GE_REG_IMM =>
-                if !(self.reg.get(&inst.bytes[2]) >= u8arr_to_u64(&inst.bytes[3..])) { self.addr += 1 },
-            Opcode::CMP_LT_REG_IMM =>
-                if !(self.reg.get(&inst.bytes[2]) < u8arr_to_u64(&inst.bytes[3..])) { self.addr += 1 },
-            Opcode::CMP_GT_REG_IMM =>
-                if !(self.reg.get(&inst.bytes[2]) > u8arr_to_u64(&inst.bytes[3..])) { self.addr += 1 },
-            _ => panic!("Non jmp instruction found.")
-        }
-    }
-
-    fn execute_arithmetic(&self, inst: Instruction) {
-        let dst = inst.bytes[2];
+    fn push(&self, data: u64) -> Result<(), Fault> {
+        let sp = self.reg.get(&STACK_POINTER);
 
-        let mut src1: u64 = 0;
-        let mut src2: u64 = 0;
+        // The stack pointer may have been moved out of the region
+        if sp <= self.stack.start as u64 || sp > self.stack.end as u64 {
+            return Err(Fault::StackOverflow);
+        }
 
-        match inst.bytes[1] >> 6 {
-            0 => {
-                src1 = self.reg.get(&inst.bytes[3]);
-                src2 = self.reg.get(&inst.bytes[4]);
+        self.write_word(sp as u32 - 1, data)?;
+        self.reg.set(STACK_POINTER, sp - 1);
+
+        Ok(())
+    }
+
+    fn pop(&self) -> Result<u64, Fault> {
+        let sp = self.reg.get(&STACK_POINTER);
+
+        if sp < self.stack.start as u64 || sp >= self.stack.end as u64 {
+            return Err(Fault::StackUnderflow);
+        }
+
+        let data = self.read_word(sp as u32)?;
+        self.reg.set(STACK_POINTER, sp + 1);
+
+        Ok(data)
+    }
+
+    fn execute_stack(&mut self, inst: Instruction) -> Result<(), Fault> {
+        match inst.opcode {
+            Opcode::PUSH => match inst.bytes[1] >> 6 {
+                0 => self.push(self.reg.get(&inst.bytes[2]))?,
+                1 => self.push(u8arr_to_u64(&inst.bytes[2..]))?,
+                _ => return Err(Fault::InvalidOption(inst.bytes[1]))
             },
-            1 => {
-                src1 = self.reg.get(&inst.bytes[3]);
-                src2 = u8arr_to_u64(&inst.bytes[4..]);
+            Opcode::POP => {
+                let data = self.pop()?;
+                self.reg.set(inst.bytes[1], data);
             },
-            2 => {
-                let d = 2 + (inst.bytes[1] & 0xF) as usize;
-                src1 = u8arr_to_u64(&inst.bytes[2..d]);
-                src2 = u8arr_to_u64(&inst.bytes[d..]);
+            Opcode::CALL => {
+                // Push the address of the next instruction, RET pops it
+                self.push(self.pc())?;
+                self.jump(u8arr_to_u32(&inst.bytes[2..]) as u64);
+            },
+            Opcode::RET => {
+                let pc = self.pop()?;
+                self.jump(pc);
             },
             _ => {}
         }
 
+        Ok(())
+    }
+
+    fn execute_load_store(&self, inst: Instruction) -> Result<(), Fault> {
+        // Move between a register and memory at a byte address, which
+        // may span two words
+        let reg = inst.bytes[2];
+        let addr = match inst.bytes[1] >> 6 {
+            0b00 => self.reg.get(&inst.bytes[3]),
+            0b01 => u8arr_to_u64(&inst.bytes[3..]),
+            _ => return Err(Fault::InvalidOption(inst.bytes[1]))
+        };
+
         match inst.opcode {
-            Opcode::ADD => self.reg.set(dst, src1 + src2),
-            Opcode::SUB => self.reg.set(dst, src1 - src2),
-            Opcode::MUL => self.reg.set(dst, src1 * src2),
-            Opcode::DIV => self.reg.set(dst, src1 / src2),
-            _ => {}
+            Opcode::LOADB => self.reg.set(reg, self.read_bytes(addr, 1)?),
+            Opcode::LOADH => self.reg.set(reg, self.read_bytes(addr, 2)?),
+            Opcode::LOADW => self.reg.set(reg, self.read_bytes(addr, 8)?),
+            Opcode::STOREB => self.write_bytes(addr, 1, self.reg.get(&reg))?,
+            Opcode::STOREH => self.write_bytes(addr, 2, self.reg.get(&reg))?,
+            _ => self.write_bytes(addr, 8, self.reg.get(&reg))?
         }
+
+        Ok(())
     }
 
-    fn execute_fp_arithmetic(&self, inst: Instruction) {
-        let dst = inst.bytes[2];
+    fn execute_comparison(&mut self, inst: Instruction) -> Result<(), Fault> {
+        let (lhs, rhs) = match inst.opcode {
+            Opcode::CMP_EQ_REG_REG |
+            Opcode::CMP_LE_REG_REG |
+            Opcode::CMP_GE_REG_REG |
+            Opcode::CMP_LT_REG_REG |
+            Opcode::CMP_GT_REG_REG |
+            Opcode::CMP_ILE_REG_REG |
+            Opcode::CMP_IGE_REG_REG |
+            Opcode::CMP_ILT_REG_REG |
+            Opcode::CMP_IGT_REG_REG |
+            Opcode::CMP_FEQ_REG_REG |
+            Opcode::CMP_FLE_REG_REG |
+            Opcode::CMP_FGE_REG_REG |
+            Opcode::CMP_FLT_REG_REG |
+            Opcode::CMP_FGT_REG_REG => (self.reg.get(&inst.bytes[1]), self.reg.get(&inst.bytes[2])),
+            _ => (self.reg.get(&inst.bytes[2]), self.immediate(inst, &inst.bytes[3..]))
+        };
+
+        let pass = match inst.opcode {
+            Opcode::CMP_EQ_REG_REG | Opcode::CMP_EQ_REG_IMM => lhs == rhs,
+            Opcode::CMP_LE_REG_REG | Opcode::CMP_LE_REG_IMM => lhs <= rhs,
+            Opcode::CMP_GE_REG_REG | Opcode::CMP_GE_REG_IMM => lhs >= rhs,
+            Opcode::CMP_LT_REG_REG | Opcode::CMP_LT_REG_IMM => lhs < rhs,
+            Opcode::CMP_GT_REG_REG | Opcode::CMP_GT_REG_IMM => lhs > rhs,
+            Opcode::CMP_ILE_REG_REG | Opcode::CMP_ILE_REG_IMM => lhs as i64 <= rhs as i64,
+            Opcode::CMP_IGE_REG_REG | Opcode::CMP_IGE_REG_IMM => lhs as i64 >= rhs as i64,
+            Opcode::CMP_ILT_REG_REG | Opcode::CMP_ILT_REG_IMM => (lhs as i64) < rhs as i64,
+            Opcode::CMP_IGT_REG_REG | Opcode::CMP_IGT_REG_IMM => lhs as i64 > rhs as i64,
+            Opcode::CMP_FEQ_REG_REG | Opcode::CMP_FEQ_REG_IMM => f64::from_bits(lhs) == f64::from_bits(rhs),
+            Opcode::CMP_FLE_REG_REG | Opcode::CMP_FLE_REG_IMM => f64::from_bits(lhs) <= f64::from_bits(rhs),
+            Opcode::CMP_FGE_REG_REG | Opcode::CMP_FGE_REG_IMM => f64::from_bits(lhs) >= f64::from_bits(rhs),
+            Opcode::CMP_FLT_REG_REG | Opcode::CMP_FLT_REG_IMM => f64::from_bits(lhs) < f64::from_bits(rhs),
+            Opcode::CMP_FGT_REG_REG | Opcode::CMP_FGT_REG_IMM => f64::from_bits(lhs) > f64::from_bits(rhs),
+            _ => true
+        };
+
+        self.compare_flags(inst.opcode, lhs, rhs);
+
+        // Skip the instruction following the comparison. If it cannot be
+        // decoded, stay put and let the next fetch report the fault.
+        if !pass {
+            if let Ok(next) = self.fetch() {
+                let (addr, offset) = next.end();
+
+                self.addr = addr;
+                self.offset = offset;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn compare_flags(&self, opcode: Opcode, lhs: u64, rhs: u64) {
+        // Set the flags as for lhs - rhs. Floats set zero when equal,
+        // negative when less and carry when unordered.
+        let flags = if opcode.is_float() {
+            let (lhs, rhs) = (f64::from_bits(lhs), f64::from_bits(rhs));
+
+            match lhs.partial_cmp(&rhs) {
+                Some(std::cmp::Ordering::Equal) => FLAG_ZERO,
+                Some(std::cmp::Ordering::Less) => FLAG_NEGATIVE,
+                Some(std::cmp::Ordering::Greater) => 0,
+                None => FLAG_CARRY
+            }
+        } else {
+            let (result, borrow) = lhs.overflowing_sub(rhs);
+            let (_, overflow) = (lhs as i64).overflowing_sub(rhs as i64);
+
+            flag(FLAG_ZERO, result == 0)
+                | flag(FLAG_NEGATIVE, (result as i64) < 0)
+                | flag(FLAG_CARRY, borrow)
+                | flag(FLAG_OVERFLOW, overflow)
+        };
+
+        self.flags.set(flags);
+    }
+
+    fn result_flags(&self, result: u64) {
+        // Set zero and negative from a result. Signed arithmetic which
+        // would overflow faults instead, and bitwise operations cannot
+        // carry, so those flags are cleared.
+        self.flags.set(flag(FLAG_ZERO, result == 0) | flag(FLAG_NEGATIVE, (result as i64) < 0));
+    }
+
+    fn execute_branch(&mut self, inst: Instruction) -> Result<(), Fault> {
+        let flags = self.flags.get();
+        let set = |mask| flags & mask != 0;
+
+        let taken = match inst.opcode {
+            Opcode::JZ => set(FLAG_ZERO),
+            Opcode::JNZ => !set(FLAG_ZERO),
+            // Signed comparisons
+            Opcode::JLT => set(FLAG_NEGATIVE) != set(FLAG_OVERFLOW),
+            Opcode::JGE => set(FLAG_NEGATIVE) == set(FLAG_OVERFLOW),
+            // Unsigned comparisons, carry is set when lhs < rhs
+            Opcode::JC => set(FLAG_CARRY),
+            Opcode::JNC => !set(FLAG_CARRY),
+            _ => false
+        };
+
+        if !taken {
+            return Ok(());
+        }
+
+        let target = &inst.bytes[2..];
+
+        if inst.bytes[1] & 0x80 != 0 {
+            // Displacements count bytes from the end of the jump
+            self.jump(self.pc().wrapping_add(u8arr_to_i64(target) as u64));
+        } else {
+            self.jump(u8arr_to_u32(target) as u64);
+        }
+
+        Ok(())
+    }
 
-        let mut src1: u64 = 0;
-        let mut src2: u64 = 0;
+    fn immediate(&self, inst: Instruction, bytes: &[u8]) -> u64 {
+        // Decode an immediate, sign extending it for signed opcodes and
+        // filling in the low bits of floats
+        if inst.opcode.is_signed() {
+            u8arr_to_i64(bytes) as u64
+        } else if inst.opcode.is_float() {
+            u8arr_to_u64(bytes).checked_shl(64 - 8 * bytes.len() as u32).unwrap_or(0)
+        } else {
+            u8arr_to_u64(bytes)
+        }
+    }
 
+    fn operands(&self, inst: Instruction) -> Result<(u64, u64), Fault> {
+        // Decode the two source operands of an arithmetic instruction
         match inst.bytes[1] >> 6 {
-            0 => {
-                src1 = self.reg.get(&inst.bytes[3]);
-                src2 = self.reg.get(&inst.bytes[4]);
-            },
-            1 => {
-                src1 = self.reg.get(&inst.bytes[3]);
-                src2 = u8arr_to_u64(&inst.bytes[4..]);
-            },
+            0 => Ok((self.reg.get(&inst.bytes[3]), self.reg.get(&inst.bytes[4]))),
+            1 => Ok((self.reg.get(&inst.bytes[3]), self.immediate(inst, &inst.bytes[4..]))),
             2 => {
-                let d = 2 + (inst.bytes[1] & 0xF) as usize;
-                src1 = u8arr_to_u64(&inst.bytes[2..d]);
-                src2 = u8arr_to_u64(&inst.bytes[d..]);
+                let d = 3 + (inst.bytes[1] & 0xF) as usize;
+                Ok((self.immediate(inst, &inst.bytes[3..d]), self.immediate(inst, &inst.bytes[d..])))
             },
-            _ => {}
+            _ => Err(Fault::InvalidOption(inst.bytes[1]))
         }
+    }
+
+    fn execute_arithmetic(&self, inst: Instruction) -> Result<(), Fault> {
+        let dst = inst.bytes[2];
+
+        let (src1, src2) = self.operands(inst)?;
+        let (lhs, rhs) = (src1 as i64, src2 as i64);
+
+        // ADD, SUB and MUL wrap around. Carry is set when the unsigned
+        // result does not fit and overflow when the signed one does not.
+        let (result, carry, overflow) = match inst.opcode {
+            Opcode::ADD => {
+                let (result, carry) = src1.overflowing_add(src2);
+                (result, carry, lhs.overflowing_add(rhs).1)
+            },
+            Opcode::SUB => {
+                let (result, borrow) = src1.overflowing_sub(src2);
+                (result, borrow, lhs.overflowing_sub(rhs).1)
+            },
+            Opcode::MUL => {
+                let (result, carry) = src1.overflowing_mul(src2);
+                (result, carry, lhs.overflowing_mul(rhs).1)
+            },
+            Opcode::DIV => (src1.checked_div(src2).ok_or(Fault::DivideByZero)?, false, false),
+            Opcode::REM => (src1.checked_rem(src2).ok_or(Fault::DivideByZero)?, false, false),
+            _ => return Ok(())
+        };
+
+        self.reg.set(dst, result);
+        self.flags.set(
+            flag(FLAG_ZERO, result == 0)
+                | flag(FLAG_NEGATIVE, (result as i64) < 0)
+                | flag(FLAG_CARRY, carry)
+                | flag(FLAG_OVERFLOW, overflow)
+        );
+
+        Ok(())
+    }
 
-        let src1 = src1 as f64;
-        let src2 = src2 as f64;
+    fn execute_signed_arithmetic(&self, inst: Instruction) -> Result<(), Fault> {
+        // Registers hold two's complement values
+        let dst = inst.bytes[2];
+
+        let (src1, src2) = self.operands(inst)?;
+        let src1 = src1 as i64;
+        let src2 = src2 as i64;
+
+        // Overflowing towards negative infinity is an underflow
+        let overflow = |negative: bool| if negative { Fault::Underflow } else { Fault::Overflow };
+
+        let result = match inst.opcode {
+            Opcode::IADD => src1.checked_add(src2).ok_or_else(|| overflow(src2 < 0))?,
+            Opcode::ISUB => src1.checked_sub(src2).ok_or_else(|| overflow(src2 > 0))?,
+            Opcode::IMUL => src1.checked_mul(src2).ok_or_else(|| overflow((src1 < 0) != (src2 < 0)))?,
+            // i64::MIN / -1 is the only other way for these to fail
+            Opcode::IDIV if src2 == 0 => return Err(Fault::DivideByZero),
+            Opcode::IDIV => src1.checked_div(src2).ok_or(Fault::Overflow)?,
+            Opcode::IREM if src2 == 0 => return Err(Fault::DivideByZero),
+            Opcode::IREM => src1.checked_rem(src2).ok_or(Fault::Overflow)?,
+            _ => return Ok(())
+        };
+
+        self.reg.set(dst, result as u64);
+        self.result_flags(result as u64);
+
+        Ok(())
+    }
+
+    fn execute_bitwise(&self, inst: Instruction) -> Result<(), Fault> {
+        let dst = inst.bytes[2];
+
+        let (src1, src2) = self.operands(inst)?;
+
+        // Shifting by the word size or more shifts every bit out
+        let shift = src2.min(64) as u32;
+
+        let result = match inst.opcode {
+            Opcode::AND => src1 & src2,
+            Opcode::OR => src1 | src2,
+            Opcode::XOR => src1 ^ src2,
+            Opcode::SHL => src1.checked_shl(shift).unwrap_or(0),
+            Opcode::SHR => src1.checked_shr(shift).unwrap_or(0),
+            Opcode::SAR => (src1 as i64).checked_shr(shift).unwrap_or(src1 as i64 >> 63) as u64,
+            // Rotations wrap around, so only the amount modulo 64 matters
+            Opcode::ROL => src1.rotate_left((src2 % 64) as u32),
+            Opcode::ROR => src1.rotate_right((src2 % 64) as u32),
+            _ => return Ok(())
+        };
+
+        self.reg.set(dst, result);
+        self.result_flags(result);
+
+        Ok(())
+    }
+
+    fn execute_fp_arithmetic(&self, inst: Instruction) -> Result<(), Fault> {
+        let dst = inst.bytes[2];
+
+        let (src1, src2) = self.operands(inst)?;
+
+        // Registers hold the bit pattern of an f64
+        let src1 = f64::from_bits(src1);
+        let src2 = f64::from_bits(src2);
+
+        let result = match inst.opcode {
+            Opcode::FADD => src1 + src2,
+            Opcode::FSUB => src1 - src2,
+            Opcode::FMUL => src1 * src2,
+            Opcode::FDIV => src1 / src2,
+            _ => return Ok(())
+        };
+
+        self.reg.set(dst, result.to_bits());
+        self.flags.set(flag(FLAG_ZERO, result == 0.0) | flag(FLAG_NEGATIVE, result < 0.0));
+
+        Ok(())
+    }
+
+    fn execute_conversion(&self, inst: Instruction) -> Result<(), Fault> {
+        let dst = inst.bytes[2];
+
+        let src = match inst.bytes[1] >> 6 {
+            0 => self.reg.get(&inst.bytes[3]),
+            1 => self.immediate(inst, &inst.bytes[3..]),
+            _ => return Err(Fault::InvalidOption(inst.bytes[1]))
+        };
 
         match inst.opcode {
-            Opcode::FADD => self.reg.set(dst, (src1 + src2) as u64),
-            Opcode::FSUB => self.reg.set(dst, (src1 - src2) as u64),
-            Opcode::FMUL => self.reg.set(dst, (src1 * src2) as u64),
-            Opcode::FDIV => self.reg.set(dst, (src1 / src2) as u64),
+            Opcode::ITOF => self.reg.set(dst, (src as i64 as f64).to_bits()),
+            // Rounds towards zero and saturates, NaN converts to 0
+            Opcode::FTOI => self.reg.set(dst, f64::from_bits(src) as i64 as u64),
             _ => {}
         }
+
+        Ok(())
     }
 
-    fn execute_not(&self, inst: Instruction) {
+    fn execute_not(&self, inst: Instruction) -> Result<(), Fault> {
         let dst = inst.bytes[2];
 
         match inst.bytes[1] >> 6 {
             0 => self.reg.set(dst, !self.reg.get(&inst.bytes[3])),
             1 => self.reg.set(dst, !u8arr_to_u64(&inst.bytes[3..])),
-            _ => {}
+            _ => return Err(Fault::InvalidOption(inst.bytes[1]))
         }
+
+        Ok(())
     }
 
-    fn execute_call(&mut self, inst: Instruction) {
-        match inst.bytes[1] {
-            // TODO: add more calls
-            // PNT
-            0x9A => print!("{}", self.mem.read_utf16(self.reg.get(&0) as u32)),
-            // HLT
-            0x9D => self.running = false,
-            _ => panic!("Unknown call {:#04X}", inst.bytes[1])
-        }
+    fn execute_file_load(&mut self, inst: Instruction) -> Result<(), Fault> {
+        // FILE_LOAD reg [path] [base] loads the module named by the UTF-16
+        // string at path, leaving a LOAD_* status in reg. Failing to load
+        // is not a fault, the program decides what to do.
+        let p = 3 + (inst.bytes[1] >> 4) as usize;
+        let path = self.read_string(u8arr_to_u32(&inst.bytes[3..p]))?;
+        let base = u8arr_to_u32(&inst.bytes[p..]);
+
+        let status = match self.load_module(&path, base) {
+            Ok(()) => LOAD_OK,
+            Err(status) => status
+        };
+
+        self.reg.set(inst.bytes[2], status);
+        Ok(())
     }
-}
 
-#[test]
-fn test_mov() {
-    let vm = VM::new();
+    fn execute_call(&mut self, inst: Instruction) -> Result<(), Fault> {
+        if let Some(host) = self.hosts.get_mut(&inst.bytes[1]) {
+            return (host.call)(&self.reg, &self.mem);
+        }
+
+        match inst.bytes[1] {
+            0x90..=0x92 => self.execute_input(inst.bytes[1])?,
+            // PNT, the UTF-16 string at the word address in R00
+            0x9A => {
+                let string = self.read_string(self.reg.get(&0) as u32)?;
+                self.io.write(&string);
+            },
+            // PNTI, R00 as a signed integer
+            0x9B => self.io.write(&(self.reg.get(&0) as i64).to_string()),
+            // PNTF, R00 as a float
+            0x9C => self.io.write(&f64::from_bits(self.reg.get(&0)).to_string()),
+            // HLT, run stops and reports the exit code
+            0x9D => self.running = false,
+            // EPNT, the UTF-16 string at the word address in R00 to stderr
+            0x9F => {
+                let string = self.read_string(self.reg.get(&0) as u32)?;
+                self.io.write_err(&string);
+            },
+            // PNTC, the character with the code point in R00
+            0x9E => {
+                let code = self.reg.get(&0).min(u32::MAX as u64) as u32;
+                let chr = std::char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER);
+
+                self.io.write(chr.encode_utf8(&mut [0; 4]));
+            },
+            call => return Err(Fault::UnknownCall(call))
+        }
+
+        Ok(())
+    }
+
+    fn execute_input(&mut self, call: u8) -> Result<(), Fault> {
+        // Input calls leave their result in R00, or set the carry flag
+        // and R00 to 0 when the input has ended or is invalid
+        let input = match call {
+            // GETC, the next character's code point
+            0x90 => self.io.read_char().map(|chr| chr as u64),
+            // GETL, the next line as a UTF-16 string at the word address
+            // in R00, giving its length
+            0x91 => match self.io.read_line() {
+                Some(line) => {
+                    let chars: Vec<u16> = line.encode_utf16().chain(Some(0)).collect();
+                    let bytes: Vec<u8> = chars.iter().flat_map(|chr| chr.to_be_bytes()).collect();
+                    let start = self.reg.get(&0) as u32;
+
+                    for i in 0..bytes.len().div_ceil(8) as u32 {
+                        self.mem.check(start.wrapping_add(i), WRITE)?;
+                    }
+
+                    self.mem.try_write_bytes(start, &bytes)?;
+                    Some(chars.len() as u64 - 1)
+                },
+                None => None
+            },
+            // GETI, a signed decimal integer read from the next line
+            _ => self.io.read_line()
+                .and_then(|line| line.trim().parse::<i64>().ok())
+                .map(|num| num as u64)
+        };
+
+        self.flags.set(self.flags.get() & !FLAG_CARRY | flag(FLAG_CARRY, input.is_none()));
+        self.reg.set(0, input.unwrap_or(0));
+
+        Ok(())
+    }
+}
+
+fn flag(mask: u8, set: bool) -> u8 {
+    if set { mask } else { 0 }
+}
+
+#[test]
+fn test_mov() {
+    let vm = VM::new();
 
     vm.reg.set(29, 12345); // <=> MOV R29 12345
     vm.execute_mov(
@@ -349,7 +1244,7 @@ fn test_mov() {
             Opcode::MOV_REG_REG,
             &[Opcode::MOV_REG_REG as u8, 4, 29]
         )
-    );
+    ).unwrap();
     assert_eq!(vm.reg.get(&4), 12345);
 
     vm.mem.write(0x2929, 54321); // <=> MOV [0x2929] 54321
@@ -359,7 +1254,7 @@ fn test_mov() {
             Opcode::MOV_REG_MEM,
             &[Opcode::MOV_REG_MEM as u8, 0, 0, 0, 0x29, 0x29]
         )
-    );
+    ).unwrap();
     assert_eq!(vm.reg.get(&0), 54321);
 
     vm.execute_mov(
@@ -368,7 +1263,7 @@ fn test_mov() {
             Opcode::MOV_MEM_MEM,
             &[Opcode::MOV_MEM_MEM as u8, 0b0001_0010, 0x27, 0x29, 0x29]
         )
-    );
+    ).unwrap();
 
     assert_eq!(vm.mem.read(0x27).unwrap(), 54321);
 
@@ -378,7 +1273,7 @@ fn test_mov() {
             Opcode::MOV_REG_IMM,
             &[Opcode::MOV_REG_IMM as u8, 7, 0x59, 0x23, 0x24, 0x25, 0x26, 0x27, 0x28, 0x29]
         )
-    );
+    ).unwrap();
 
     assert_eq!(vm.reg.get(&0x59), 0x23242526272829);
 
@@ -388,7 +1283,7 @@ fn test_mov() {
             Opcode::MOV_MEM_IMM,
             &[Opcode::MOV_MEM_IMM as u8, 0b0010_0101, 0x92, 0xCA, 0xAA, 0xBB, 0xCC, 0xDD, 0xEE]
         )
-    );
+    ).unwrap();
 
     assert_eq!(vm.mem.read(0x92CA).unwrap(), 0xAABBCCDDEE);
 }
@@ -398,8 +1293,8 @@ fn test_jmp() {
     let mut vm = VM::new();
 
     vm.mem.write_bytes(0,
-        // JMP [0x3]
-        &[Opcode::JMP_IMM as u8, 1, 3]
+        // JMP [0x18]
+        &[Opcode::JMP_IMM as u8, 1, 0x18]
     );
     vm.mem.write_bytes(1,
         // MOV [0x92CA] 0xAABBCCDDEE
@@ -410,7 +1305,7 @@ fn test_jmp() {
         &[Opcode::MOV_MEM_IMM as u8, 0b0010_0101, 0x93, 0xCA, 0xAA, 0xBB, 0xFF, 0xDD, 0xEE]
     );
 
-    vm.reg.set(29, 8); // MOV R29 8
+    vm.reg.set(29, 0x40); // MOV R29 0x40
     vm.mem.write_bytes(5,
         // JMP R29
         &[Opcode::JMP_REG as u8, 29]
@@ -425,8 +1320,8 @@ fn test_jmp() {
     );
 
     vm.mem.write_bytes(10,
-        // JSR 0xEE
-        &[Opcode::JSR as u8, 1, 0xEE]
+        // JSR 0x770
+        &[Opcode::JSR as u8, 2, 0x07, 0x70]
     );
 
     vm.mem.write_bytes(0xEE,
@@ -442,8 +1337,12 @@ fn test_jmp() {
         // MOV [0x95CA] 0xAABBCCDDFF
         &[Opcode::MOV_MEM_IMM as u8, 0b0010_0101, 0x95, 0xCA, 0xAA, 0xBB, 0xCC, 0xDD, 0xFF]
     );
+    vm.mem.write_bytes(13,
+        // CAL HLT
+        &[Opcode::CAL as u8, 0x9D]
+    );
 
-    vm.run();
+    assert_eq!(vm.run().unwrap(), ExitReason::Halted(0));
 
     assert_eq!(vm.mem.read(0x92CA), None);
     assert_eq!(vm.mem.read(0x93CA).unwrap(), 0xAABBFFDDEE);
@@ -460,33 +1359,990 @@ fn test_comparison() {
     vm.reg.set(2, 2);
     let instructions: Vec<&[u8]> = vec![
         &[Opcode::CMP_EQ_REG_REG as u8, 0, 3], // CMPeq R00, R03
-        &[Opcode::JMP_IMM as u8, 1, 0x3], // JMP [0x3]
+        &[Opcode::JMP_IMM as u8, 1, 0x18], // JMP [0x18]
         &[Opcode::MOV_MEM_IMM as u8, 0b0001_0001, 0x2, 0xFF], // MOV [0x2] 0xFF
         &[Opcode::CMP_GE_REG_REG as u8, 1, 0], // CMPge R01 R00
-        &[Opcode::JMP_IMM as u8, 1, 0x6], // JMP [0x6]
+        &[Opcode::JMP_IMM as u8, 1, 0x30], // JMP [0x30]
         &[Opcode::MOV_MEM_IMM as u8, 0b0001_0001, 0x2, 0xFF], // MOV [0x2] 0xFF
         &[Opcode::CMP_LE_REG_REG as u8, 1, 2], // CMPle R01 R02
-        &[Opcode::JMP_IMM as u8, 1, 0x9], // JMP [0x9]
+        &[Opcode::JMP_IMM as u8, 1, 0x48], // JMP [0x48]
         &[Opcode::MOV_MEM_IMM as u8, 0b0001_0001, 0x2, 0xFF], // MOV [0x2] 0xFF
         &[Opcode::CMP_GE_REG_REG as u8, 0, 3], // CMPge R00 R03
-        &[Opcode::JMP_IMM as u8, 1, 0xC], // JMP [0xC]
+        &[Opcode::JMP_IMM as u8, 1, 0x60], // JMP [0x60]
         &[Opcode::MOV_MEM_IMM as u8, 0b0001_0001, 0x2, 0xFF], // MOV [0x2] 0xFF
         &[Opcode::CMP_LE_REG_REG as u8, 0, 3], // CMPle R00 R03
-        &[Opcode::JMP_IMM as u8, 1, 0xF], // JMP [0xF]
+        &[Opcode::JMP_IMM as u8, 1, 0x78], // JMP [0x78]
         &[Opcode::MOV_MEM_IMM as u8, 0b0001_0001, 0x2, 0xFF], // MOV [0x2] 0xFF
         &[Opcode::CMP_GT_REG_REG as u8, 2, 1], // CMPgt R02 R01
-        &[Opcode::JMP_IMM as u8, 1, 0x12], // JMP [0x12]
+        &[Opcode::JMP_IMM as u8, 1, 0x90], // JMP [0x90]
         &[Opcode::MOV_MEM_IMM as u8, 0b0001_0001, 0x2, 0xFF], // MOV [0x2] 0xFF
         &[Opcode::CMP_LT_REG_REG as u8, 0, 2], // CMPlt R00 R02
-        &[Opcode::JMP_IMM as u8, 1, 0x15], // JMP [0x15]
+        &[Opcode::JMP_IMM as u8, 1, 0xA8], // JMP [0xA8]
         &[Opcode::MOV_MEM_IMM as u8, 0b0001_0001, 0x2, 0xFF], // MOV [0x2] 0xFF
+        &[Opcode::CAL as u8, 0x9D], // CAL HLT
     ];
 
     for (i, inst) in instructions.iter().enumerate() {
         vm.mem.write_bytes(i as u32, inst);
     }
 
-    vm.run();
+    vm.run().unwrap();
 
     assert_ne!(vm.mem.read(0x2).unwrap(), 0xFF);
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_faults() {
+    let mut vm = VM::new();
+
+    vm.mem.write_bytes(0,
+        // DIV R01 0x10 0x00
+        &[Opcode::DIV as u8, 0b10_000001, 1, 0x10, 0x00]
+    );
+
+    assert_eq!(vm.run(), Err(VmError::new(0, Some(Opcode::DIV), Fault::DivideByZero)));
+
+    let mut vm = VM::new();
+
+    vm.mem.write_bytes(0, &[Opcode::NOT as u8, 0b00_000000, 1, 2]);
+    vm.mem.write_bytes(1,
+        // MOV R00 [0x2929]
+        &[Opcode::MOV_REG_MEM as u8, 0, 0, 0, 0x29, 0x29]
+    );
+
+    assert_eq!(vm.run(), Err(VmError::new(1, Some(Opcode::MOV_REG_MEM), Fault::InvalidAddress(0x2929))));
+
+    let mut vm = VM::new();
+
+    vm.mem.write_bytes(0, &[Opcode::CAL as u8, 0x42]);
+
+    assert_eq!(vm.run().unwrap_err().cause, Fault::UnknownCall(0x42));
+
+    let mut vm = VM::new();
+
+    vm.mem.write_bytes(0, &[Opcode::ADD as u8, 0b11_000000, 1, 2, 3]);
+
+    assert_eq!(vm.run().unwrap_err().cause, Fault::InvalidOption(0b11_000000));
+
+    // Instructions and strings running to the end of memory
+    let mut vm = VM::with_io(Box::new(io::Buffer::default()));
+
+    vm.mem.write_bytes(0xFFFF_FFFF, &[Opcode::MOV_MEM_IMM as u8, 0b0100_1000, 0, 0, 0, 0]);
+    vm.jump(0xFFFF_FFFF * 8);
+    assert_eq!(vm.resume(), Err(VmError::new(0xFFFF_FFFF, Some(Opcode::MOV_MEM_IMM), Fault::InvalidAddress(0))));
+
+    vm.mem.write(0xFFFF_FFFF, 0x0068_0069_0021_0021);
+    vm.reg.set(0, 0xFFFF_FFFF);
+    assert_eq!(vm.execute_call(Instruction::with_data(Opcode::CAL, &[Opcode::CAL as u8, 0x9A])), Ok(()));
+}
+
+#[test]
+fn test_halt() {
+    let mut vm = VM::new();
+
+    vm.mem.write_bytes(0,
+        // MOV R00 0x2A
+        &[Opcode::MOV_REG_IMM as u8, 0b0001_0000, 0, 0x2A]
+    );
+    vm.mem.write_bytes(1,
+        // CAL HLT, MOV R00 0x00 in the same word must not run
+        &[Opcode::CAL as u8, 0x9D, Opcode::MOV_REG_IMM as u8, 0b0001_0000, 0, 0]
+    );
+
+    assert_eq!(vm.run().unwrap(), ExitReason::Halted(0x2A));
+
+    let mut vm = VM::new();
+
+    vm.mem.write_bytes(0,
+        // JMP [0x18]
+        &[Opcode::JMP_IMM as u8, 1, 0x18]
+    );
+    vm.mem.write_bytes(3,
+        // MOV R01 0x01
+        &[Opcode::MOV_REG_IMM as u8, 0b0001_0000, 1, 1]
+    );
+
+    // Jumping over the gap is fine, running off the end is not
+    assert_eq!(vm.run(), Err(VmError::new(4, None, Fault::EndOfProgram)));
+    assert_eq!(vm.reg.get(&1), 1);
+}
+
+#[test]
+fn test_step() {
+    let mut vm = VM::new();
+
+    vm.mem.write_bytes(0, &[
+        // MOV R01 0x01, MOV R02 R01 (packed into one word)
+        Opcode::MOV_REG_IMM as u8, 0b0001_0000, 1, 1,
+        Opcode::MOV_REG_REG as u8, 2, 1, 0
+    ]);
+    vm.mem.write_bytes(1,
+        // ADD R03 R01 R02
+        &[Opcode::ADD as u8, 0b00_000000, 3, 1, 2]
+    );
+    vm.mem.write_bytes(2,
+        // CAL HLT
+        &[Opcode::CAL as u8, 0x9D]
+    );
+
+    let decoded = match vm.step().unwrap() {
+        Step::Executed(decoded) => decoded,
+        Step::OutOfFuel => unreachable!()
+    };
+
+    assert_eq!((decoded.addr, decoded.offset), (0, 0));
+    assert_eq!(decoded.instruction().to_string(), "05 10 01 01");
+    assert_eq!((vm.addr, vm.offset), (0, 4));
+    assert_eq!(vm.reg.get(&2), 0);
+
+    assert!(matches!(vm.step().unwrap(), Step::Executed(decoded) if decoded.opcode == Opcode::MOV_REG_REG));
+    assert_eq!(vm.reg.get(&2), 1);
+
+    assert_eq!(vm.run_until(0x10).unwrap(), ExitReason::Breakpoint(0x10));
+    assert_eq!(vm.reg.get(&3), 2);
+    assert!(vm.running);
+
+    assert_eq!(vm.resume().unwrap(), ExitReason::Halted(0));
+    assert!(!vm.running);
+
+    // From the loop head, each call runs the loop once more
+    let mut vm = VM::new();
+
+    vm.mem.write_bytes(0, &[
+        Opcode::ADD as u8, 0b01_000001, 1, 1, 1, // ADD R01 R01 0x01
+        Opcode::JMP_IMM as u8, 1, 0 // JMP [0x00]
+    ]);
+
+    assert_eq!(vm.run_until(0).unwrap(), ExitReason::Breakpoint(0));
+    assert_eq!(vm.run_until(0).unwrap(), ExitReason::Breakpoint(0));
+    assert_eq!(vm.reg.get(&1), 2);
+
+    // Instructions packed in the same word are told apart
+    assert_eq!(vm.run_until(5).unwrap(), ExitReason::Breakpoint(5));
+    assert_eq!(vm.reg.get(&1), 3);
+}
+
+#[test]
+fn test_fuel() {
+    let mut vm = VM::new();
+
+    vm.mem.write_bytes(0,
+        // ADD R01 R01 0x01
+        &[Opcode::ADD as u8, 0b01_000001, 1, 1, 1]
+    );
+    vm.mem.write_bytes(1,
+        // CMPlt R01 0x0A
+        &[Opcode::CMP_LT_REG_IMM as u8, 0b0001_0000, 1, 0x0A]
+    );
+    vm.mem.write_bytes(2,
+        // JMP [0x0]
+        &[Opcode::JMP_IMM as u8, 1, 0]
+    );
+    vm.mem.write_bytes(3,
+        // CAL HLT
+        &[Opcode::CAL as u8, 0x9D]
+    );
+
+    vm.refuel(6);
+
+    assert_eq!(vm.run().unwrap(), ExitReason::OutOfFuel);
+    assert_eq!(vm.reg.get(&1), 2);
+    assert_eq!(vm.fuel, Some(0));
+    assert_eq!(vm.step().unwrap(), Step::OutOfFuel);
+
+    vm.refuel(1000);
+
+    assert_eq!(vm.resume().unwrap(), ExitReason::Halted(0));
+    assert_eq!(vm.reg.get(&1), 10);
+}
+
+#[test]
+fn test_load() {
+    use container::{Section, SectionKind};
+
+    let mut vm = VM::new();
+    let mut container = Container::new(0x10);
+
+    container.sections.push(Section {
+        kind: SectionKind::Code,
+        load: 0x10,
+        bytes: vec![
+            // MOV R00 [0x20]
+            Opcode::MOV_REG_MEM as u8, 0, 0, 0, 0, 0x20, 0, 0,
+            // CAL HLT
+            Opcode::CAL as u8, 0x9D
+        ]
+    });
+    container.sections.push(Section {
+        kind: SectionKind::Data,
+        load: 0x20,
+        bytes: vec![0, 0, 0, 0, 0, 0, 0, 0x2A]
+    });
+
+    vm.load(&Container::parse(&container.to_bytes().unwrap()).unwrap()).unwrap();
+
+    assert_eq!(vm.addr, 0x10);
+    assert_eq!(vm.run().unwrap(), ExitReason::Halted(42));
+
+    // Nothing is written to memory when validation fails
+    let mut vm = VM::new();
+    container.entry = 0x20;
+
+    assert_eq!(vm.load(&container), Err(LoadError::EntryOutsideCode(0x20)));
+    assert_eq!(vm.mem.read(0x10), None);
+}
+
+#[test]
+fn test_bitwise() {
+    let vm = VM::new();
+
+    vm.reg.set(1, 0b1100);
+    vm.reg.set(2, 0b1010);
+    vm.reg.set(3, 0x8000_0000_0000_00F0);
+
+    // AND R04 R01 R02
+    vm.execute_bitwise(Instruction::with_data(Opcode::AND, &[Opcode::AND as u8, 0b00_000000, 4, 1, 2])).unwrap();
+    assert_eq!(vm.reg.get(&4), 0b1000);
+
+    // OR R04 R01 0x03
+    vm.execute_bitwise(Instruction::with_data(Opcode::OR, &[Opcode::OR as u8, 0b01_000001, 4, 1, 3])).unwrap();
+    assert_eq!(vm.reg.get(&4), 0b1111);
+
+    // XOR R04 0x0F 0x05
+    vm.execute_bitwise(Instruction::with_data(Opcode::XOR, &[Opcode::XOR as u8, 0b10_000001, 4, 0x0F, 0x05])).unwrap();
+    assert_eq!(vm.reg.get(&4), 0b1010);
+
+    // SHL R04 R01 0x40, every bit is shifted out
+    vm.execute_bitwise(Instruction::with_data(Opcode::SHL, &[Opcode::SHL as u8, 0b01_000001, 4, 1, 0x40])).unwrap();
+    assert_eq!(vm.reg.get(&4), 0);
+
+    // SHR R04 R03 0x04
+    vm.execute_bitwise(Instruction::with_data(Opcode::SHR, &[Opcode::SHR as u8, 0b01_000001, 4, 3, 4])).unwrap();
+    assert_eq!(vm.reg.get(&4), 0x0800_0000_0000_000F);
+
+    // SAR R04 R03 0x04
+    vm.execute_bitwise(Instruction::with_data(Opcode::SAR, &[Opcode::SAR as u8, 0b01_000001, 4, 3, 4])).unwrap();
+    assert_eq!(vm.reg.get(&4), 0xF800_0000_0000_000F);
+
+    // SAR R04 R03 0xFF fills with the sign bit
+    vm.execute_bitwise(Instruction::with_data(Opcode::SAR, &[Opcode::SAR as u8, 0b01_000001, 4, 3, 0xFF])).unwrap();
+    assert_eq!(vm.reg.get(&4), u64::MAX);
+
+    // ROL R04 R03 0x04
+    vm.execute_bitwise(Instruction::with_data(Opcode::ROL, &[Opcode::ROL as u8, 0b01_000001, 4, 3, 4])).unwrap();
+    assert_eq!(vm.reg.get(&4), 0x0000_0000_0000_0F08);
+
+    // ROR R04 R03 0x44, the same as rotating by 4
+    vm.execute_bitwise(Instruction::with_data(Opcode::ROR, &[Opcode::ROR as u8, 0b01_000001, 4, 3, 0x44])).unwrap();
+    assert_eq!(vm.reg.get(&4), 0x0800_0000_0000_000F);
+}
+
+#[test]
+fn test_swp() {
+    let vm = VM::new();
+
+    vm.reg.set(1, 0x29);
+    vm.reg.set(2, 0x42);
+    vm.mem.write(0x10, 0x1000);
+    vm.mem.write(0x2929, 0x2000);
+
+    // SWP R01 R02
+    vm.execute_mov(Instruction::with_data(Opcode::SWP, &[Opcode::SWP as u8, 0x11, 1, 2])).unwrap();
+    assert_eq!((vm.reg.get(&1), vm.reg.get(&2)), (0x42, 0x29));
+
+    // SWP R01 [0x10]
+    vm.execute_mov(Instruction::with_data(Opcode::SWP, &[Opcode::SWP as u8, 0x12, 1, 0, 0x10])).unwrap();
+    assert_eq!((vm.reg.get(&1), vm.mem.read(0x10)), (0x1000, Some(0x42)));
+
+    // SWP [0x10] [0x2929]
+    vm.execute_mov(Instruction::with_data(Opcode::SWP, &[Opcode::SWP as u8, 0x22, 0, 0x10, 0x29, 0x29])).unwrap();
+    assert_eq!((vm.mem.read(0x10), vm.mem.read(0x2929)), (Some(0x2000), Some(0x42)));
+
+    // SWP R02 [0x30] faults without changing R02
+    let fault = vm.execute_mov(Instruction::with_data(Opcode::SWP, &[Opcode::SWP as u8, 0x12, 2, 0, 0x30]));
+    assert_eq!(fault, Err(Fault::InvalidAddress(0x30)));
+    assert_eq!(vm.reg.get(&2), 0x29);
+}
+
+#[test]
+fn test_signed_arithmetic() {
+    let vm = VM::new();
+
+    vm.reg.set(1, -7i64 as u64);
+    vm.reg.set(2, 2);
+    vm.reg.set(3, i64::MIN as u64);
+
+    // IADD R04 R02 0xFB, the immediate is -5
+    vm.execute_signed_arithmetic(Instruction::with_data(Opcode::IADD, &[Opcode::IADD as u8, 0b01_000001, 4, 2, 0xFB])).unwrap();
+    assert_eq!(vm.reg.get(&4) as i64, -3);
+
+    // IDIV R04 R01 R02 rounds towards zero
+    vm.execute_signed_arithmetic(Instruction::with_data(Opcode::IDIV, &[Opcode::IDIV as u8, 0b00_000000, 4, 1, 2])).unwrap();
+    assert_eq!(vm.reg.get(&4) as i64, -3);
+
+    // IREM R04 R01 R02
+    vm.execute_signed_arithmetic(Instruction::with_data(Opcode::IREM, &[Opcode::IREM as u8, 0b00_000000, 4, 1, 2])).unwrap();
+    assert_eq!(vm.reg.get(&4) as i64, -1);
+
+    // IMUL R04 0xFE 0x03
+    vm.execute_signed_arithmetic(Instruction::with_data(Opcode::IMUL, &[Opcode::IMUL as u8, 0b10_000001, 4, 0xFE, 0x03])).unwrap();
+    assert_eq!(vm.reg.get(&4) as i64, -6);
+
+    // REM R04 R01 R02 treats R01 as unsigned
+    vm.execute_arithmetic(Instruction::with_data(Opcode::REM, &[Opcode::REM as u8, 0b00_000000, 4, 1, 2])).unwrap();
+    assert_eq!(vm.reg.get(&4), 1);
+
+    // ISUB R04 R03 0x01
+    let fault = vm.execute_signed_arithmetic(Instruction::with_data(Opcode::ISUB, &[Opcode::ISUB as u8, 0b01_000001, 4, 3, 1]));
+    assert_eq!(fault, Err(Fault::Underflow));
+
+    // IDIV R04 R03 0xFF, the immediate is -1
+    let fault = vm.execute_signed_arithmetic(Instruction::with_data(Opcode::IDIV, &[Opcode::IDIV as u8, 0b01_000001, 4, 3, 0xFF]));
+    assert_eq!(fault, Err(Fault::Overflow));
+
+    // IREM R04 R01 0x00
+    let fault = vm.execute_signed_arithmetic(Instruction::with_data(Opcode::IREM, &[Opcode::IREM as u8, 0b01_000001, 4, 1, 0]));
+    assert_eq!(fault, Err(Fault::DivideByZero));
+}
+
+#[test]
+fn test_typed_comparison() {
+    let mut vm = VM::new();
+
+    vm.reg.set(1, -1i64 as u64);
+    vm.reg.set(2, 1);
+
+    // JMP [0x00], skipped by failing comparisons
+    vm.mem.write_bytes(0, &[Opcode::JMP_IMM as u8, 1, 0]);
+
+    // CMPILT R01 R02 passes, where CMPLT would not
+    vm.execute_comparison(Instruction::with_data(Opcode::CMP_ILT_REG_REG, &[Opcode::CMP_ILT_REG_REG as u8, 1, 2])).unwrap();
+    assert_eq!(vm.pc(), 0);
+
+    vm.execute_comparison(Instruction::with_data(Opcode::CMP_LT_REG_REG, &[Opcode::CMP_LT_REG_REG as u8, 1, 2])).unwrap();
+    assert_eq!(vm.pc(), 3);
+
+    // CMPIGE R01 0xFF, the immediate is -1
+    vm.jump(0);
+    vm.execute_comparison(Instruction::with_data(Opcode::CMP_IGE_REG_IMM, &[Opcode::CMP_IGE_REG_IMM as u8, 0b0001_0000, 1, 0xFF])).unwrap();
+    assert_eq!(vm.pc(), 0);
+
+    // CMPILTZ R02
+    vm.execute_comparison(Instruction::with_data(Opcode::CMP_ILT_REG_IMM, &[Opcode::CMP_ILT_REG_IMM as u8, 0, 2])).unwrap();
+    assert_eq!(vm.pc(), 3);
+
+    // CMPFLT R01 R02, 1.5 < 2.25 passes
+    vm.jump(0);
+    vm.reg.set(1, 1.5f64.to_bits());
+    vm.reg.set(2, 2.25f64.to_bits());
+    vm.execute_comparison(Instruction::with_data(Opcode::CMP_FLT_REG_REG, &[Opcode::CMP_FLT_REG_REG as u8, 1, 2])).unwrap();
+    assert_eq!(vm.pc(), 0);
+
+    // CMPFGT R01 0x4000, 1.5 > 2.0 fails
+    vm.execute_comparison(Instruction::with_data(Opcode::CMP_FGT_REG_IMM, &[Opcode::CMP_FGT_REG_IMM as u8, 0b0010_0000, 1, 0x40, 0x00])).unwrap();
+    assert_eq!(vm.pc(), 3);
+
+    // CMPFEQ R05 R05 fails for NaN
+    vm.jump(0);
+    vm.reg.set(5, f64::NAN.to_bits());
+    vm.execute_comparison(Instruction::with_data(Opcode::CMP_FEQ_REG_REG, &[Opcode::CMP_FEQ_REG_REG as u8, 5, 5])).unwrap();
+    assert_eq!(vm.pc(), 3);
+}
+
+#[test]
+fn test_float() {
+    let vm = VM::new();
+
+    vm.reg.set(1, 1.5f64.to_bits());
+    vm.reg.set(2, 2.25f64.to_bits());
+    vm.reg.set(3, -7i64 as u64);
+
+    // FADD R04 R01 R02
+    vm.execute_fp_arithmetic(Instruction::with_data(Opcode::FADD, &[Opcode::FADD as u8, 0b00_000000, 4, 1, 2])).unwrap();
+    assert_eq!(f64::from_bits(vm.reg.get(&4)), 3.75);
+
+    // FMUL R04 R01 0x4000, the immediate is 2.0
+    vm.execute_fp_arithmetic(Instruction::with_data(Opcode::FMUL, &[Opcode::FMUL as u8, 0b01_000010, 4, 1, 0x40, 0x00])).unwrap();
+    assert_eq!(f64::from_bits(vm.reg.get(&4)), 3.0);
+
+    // FDIV R04 R01 0x00 gives infinity
+    vm.execute_fp_arithmetic(Instruction::with_data(Opcode::FDIV, &[Opcode::FDIV as u8, 0b01_000001, 4, 1, 0x00])).unwrap();
+    assert_eq!(f64::from_bits(vm.reg.get(&4)), f64::INFINITY);
+
+    // ITOF R04 R03
+    vm.execute_conversion(Instruction::with_data(Opcode::ITOF, &[Opcode::ITOF as u8, 0b00_000000, 4, 3])).unwrap();
+    assert_eq!(f64::from_bits(vm.reg.get(&4)), -7.0);
+
+    // FTOI R04 R02
+    vm.execute_conversion(Instruction::with_data(Opcode::FTOI, &[Opcode::FTOI as u8, 0b00_000000, 4, 2])).unwrap();
+    assert_eq!(vm.reg.get(&4), 2);
+}
+
+#[test]
+fn test_stack() {
+    let mut vm = VM::new();
+
+    vm.mem.write_bytes(0,
+        // MOV R01 0x03
+        &[Opcode::MOV_REG_IMM as u8, 0b0001_0000, 1, 3]
+    );
+    vm.mem.write_bytes(1,
+        // CALL [0x80]
+        &[Opcode::CALL as u8, 1, 0x80]
+    );
+    vm.mem.write_bytes(2,
+        // POP R00
+        &[Opcode::POP as u8, 0]
+    );
+    vm.mem.write_bytes(3,
+        // CAL HLT
+        &[Opcode::CAL as u8, 0x9D]
+    );
+
+    // Recursive sum of R01 down to 1, left on the stack
+    vm.mem.write_bytes(0x10,
+        // CMPEQZ R01
+        &[Opcode::CMP_EQ_REG_IMM as u8, 0, 1]
+    );
+    vm.mem.write_bytes(0x11,
+        // JMP [0xC0]
+        &[Opcode::JMP_IMM as u8, 1, 0xC0]
+    );
+    vm.mem.write_bytes(0x12,
+        // PUSH R01
+        &[Opcode::PUSH as u8, 0b00_000000, 1]
+    );
+    vm.mem.write_bytes(0x13,
+        // SUB R01 R01 0x01
+        &[Opcode::SUB as u8, 0b01_000001, 1, 1, 1]
+    );
+    vm.mem.write_bytes(0x14,
+        // CALL [0x80]
+        &[Opcode::CALL as u8, 1, 0x80]
+    );
+    vm.mem.write_bytes(0x15,
+        // POP R02, the result, then POP R03 this call's R01
+        &[Opcode::POP as u8, 2, Opcode::POP as u8, 3]
+    );
+    vm.mem.write_bytes(0x16,
+        // ADD R02 R02 R03
+        &[Opcode::ADD as u8, 0b00_000000, 2, 2, 3]
+    );
+    vm.mem.write_bytes(0x17,
+        // JMP [0xC8]
+        &[Opcode::JMP_IMM as u8, 1, 0xC8]
+    );
+    vm.mem.write_bytes(0x18,
+        // MOV R02 0x00
+        &[Opcode::MOV_REG_IMM as u8, 0b0001_0000, 2, 0]
+    );
+    vm.mem.write_bytes(0x19,
+        // POP R04, the return address, then PUSH R02 and PUSH R04
+        &[Opcode::POP as u8, 4, Opcode::PUSH as u8, 0b00_000000, 2, Opcode::PUSH as u8, 0b00_000000, 4]
+    );
+    vm.mem.write_bytes(0x1A,
+        // RET
+        &[Opcode::RET as u8]
+    );
+
+    assert_eq!(vm.run().unwrap(), ExitReason::Halted(6));
+    assert_eq!(vm.reg.get(&STACK_POINTER), vm.stack.end as u64);
+
+    // Overflowing and underflowing the stack fault
+    let mut vm = VM::new();
+    vm.stack = 0x100..0x102;
+
+    vm.mem.write_bytes(0,
+        // PUSH 0x29
+        &[Opcode::PUSH as u8, 0b01_000001, 0x29]
+    );
+    vm.mem.write_bytes(1,
+        // JMP [0x0]
+        &[Opcode::JMP_IMM as u8, 1, 0]
+    );
+
+    let err = vm.run().unwrap_err();
+    assert_eq!((err.addr, err.cause), (0, Fault::StackOverflow));
+    assert_eq!(vm.mem.read(0x100), Some(0x29));
+    assert_eq!(vm.mem.read(0xFF), None);
+
+    vm.mem.write_bytes(0,
+        // RET
+        &[Opcode::RET as u8]
+    );
+
+    assert_eq!(vm.run().unwrap_err().cause, Fault::StackUnderflow);
+}
+
+#[test]
+fn test_flags() {
+    let mut vm = VM::new();
+
+    vm.reg.set(1, 5);
+    vm.reg.set(2, 7);
+
+    // CMPEQ R01 R02, 5 - 7 borrows and is negative
+    vm.execute_comparison(Instruction::with_data(Opcode::CMP_EQ_REG_REG, &[Opcode::CMP_EQ_REG_REG as u8, 1, 2])).unwrap();
+    assert_eq!(vm.flags.get(), FLAG_NEGATIVE | FLAG_CARRY);
+
+    // CMPEQ R01 0x05
+    vm.execute_comparison(Instruction::with_data(Opcode::CMP_EQ_REG_IMM, &[Opcode::CMP_EQ_REG_IMM as u8, 0b0001_0000, 1, 5])).unwrap();
+    assert_eq!(vm.flags.get(), FLAG_ZERO);
+
+    // CMPILT R03 R01 with R03 = i64::MIN overflows
+    vm.reg.set(3, i64::MIN as u64);
+    vm.execute_comparison(Instruction::with_data(Opcode::CMP_ILT_REG_REG, &[Opcode::CMP_ILT_REG_REG as u8, 3, 1])).unwrap();
+    assert_eq!(vm.flags.get(), FLAG_OVERFLOW);
+
+    // SUB R04 R02 R02
+    vm.execute_arithmetic(Instruction::with_data(Opcode::SUB, &[Opcode::SUB as u8, 0b00_000000, 4, 2, 2])).unwrap();
+    assert_eq!(vm.flags.get(), FLAG_ZERO);
+
+    // IADD R04 R01 0xF0, 5 - 16
+    vm.execute_signed_arithmetic(Instruction::with_data(Opcode::IADD, &[Opcode::IADD as u8, 0b01_000001, 4, 1, 0xF0])).unwrap();
+    assert_eq!(vm.flags.get(), FLAG_NEGATIVE);
+}
+
+#[test]
+fn test_carry_overflow() {
+    let mut vm = VM::new();
+
+    vm.reg.set(1, u64::MAX);
+    vm.reg.set(3, i64::MAX as u64);
+    vm.reg.set(5, i64::MIN as u64);
+
+    // ADD R02 R01 0x02 wraps around to 1
+    vm.execute_arithmetic(Instruction::with_data(Opcode::ADD, &[Opcode::ADD as u8, 0b01_000001, 2, 1, 2])).unwrap();
+    assert_eq!(vm.flags.get(), FLAG_CARRY);
+    assert_eq!(vm.reg.get(&2), 1);
+    vm.jump(0);
+    vm.execute_branch(Instruction::with_data(Opcode::JC, &[Opcode::JC as u8, 1, 0x40])).unwrap();
+    assert_eq!(vm.pc(), 0x40);
+    vm.jump(0);
+    vm.execute_branch(Instruction::with_data(Opcode::JNC, &[Opcode::JNC as u8, 1, 0x40])).unwrap();
+    assert_ne!(vm.pc(), 0x40);
+
+    // ADD R02 R02 R03, 1 + i64::MAX is negative as a signed number
+    vm.execute_arithmetic(Instruction::with_data(Opcode::ADD, &[Opcode::ADD as u8, 0b00_000000, 2, 2, 3])).unwrap();
+    assert_eq!(vm.flags.get(), FLAG_NEGATIVE | FLAG_OVERFLOW);
+    vm.jump(0);
+    vm.execute_branch(Instruction::with_data(Opcode::JNC, &[Opcode::JNC as u8, 1, 0x40])).unwrap();
+    assert_eq!(vm.pc(), 0x40);
+    vm.jump(0);
+    vm.execute_branch(Instruction::with_data(Opcode::JGE, &[Opcode::JGE as u8, 1, 0x40])).unwrap();
+    assert_eq!(vm.pc(), 0x40);
+    vm.jump(0);
+    vm.execute_branch(Instruction::with_data(Opcode::JLT, &[Opcode::JLT as u8, 1, 0x40])).unwrap();
+    assert_ne!(vm.pc(), 0x40);
+
+    // SUB R04 R05 0x01, i64::MIN - 1 wraps to i64::MAX
+    vm.execute_arithmetic(Instruction::with_data(Opcode::SUB, &[Opcode::SUB as u8, 0b01_000001, 4, 5, 1])).unwrap();
+    assert_eq!(vm.flags.get(), FLAG_OVERFLOW);
+    vm.jump(0);
+    vm.execute_branch(Instruction::with_data(Opcode::JLT, &[Opcode::JLT as u8, 1, 0x40])).unwrap();
+    assert_eq!(vm.pc(), 0x40);
+    vm.jump(0);
+    vm.execute_branch(Instruction::with_data(Opcode::JGE, &[Opcode::JGE as u8, 1, 0x40])).unwrap();
+    assert_ne!(vm.pc(), 0x40);
+
+    // SUB R04 0x01 0x02 borrows
+    vm.execute_arithmetic(Instruction::with_data(Opcode::SUB, &[Opcode::SUB as u8, 0b10_000001, 4, 1, 2])).unwrap();
+    assert_eq!(vm.flags.get(), FLAG_NEGATIVE | FLAG_CARRY);
+    vm.jump(0);
+    vm.execute_branch(Instruction::with_data(Opcode::JC, &[Opcode::JC as u8, 1, 0x40])).unwrap();
+    assert_eq!(vm.pc(), 0x40);
+    vm.jump(0);
+    vm.execute_branch(Instruction::with_data(Opcode::JLT, &[Opcode::JLT as u8, 1, 0x40])).unwrap();
+    assert_eq!(vm.pc(), 0x40);
+    vm.jump(0);
+    vm.execute_branch(Instruction::with_data(Opcode::JNZ, &[Opcode::JNZ as u8, 1, 0x40])).unwrap();
+    assert_eq!(vm.pc(), 0x40);
+
+    // MUL R06 R01 0x02 carries, but -1 * 2 fits as a signed number
+    vm.execute_arithmetic(Instruction::with_data(Opcode::MUL, &[Opcode::MUL as u8, 0b01_000001, 6, 1, 2])).unwrap();
+    assert_eq!(vm.flags.get(), FLAG_NEGATIVE | FLAG_CARRY);
+    assert_eq!(vm.reg.get(&6), u64::MAX - 1);
+
+    // MUL R06 R03 0x02 fits unsigned but overflows signed
+    vm.execute_arithmetic(Instruction::with_data(Opcode::MUL, &[Opcode::MUL as u8, 0b01_000001, 6, 3, 2])).unwrap();
+    assert_eq!(vm.flags.get(), FLAG_NEGATIVE | FLAG_OVERFLOW);
+
+    // SUB R06 R06 R06
+    vm.execute_arithmetic(Instruction::with_data(Opcode::SUB, &[Opcode::SUB as u8, 0b00_000000, 6, 6, 6])).unwrap();
+    assert_eq!(vm.flags.get(), FLAG_ZERO);
+    vm.jump(0);
+    vm.execute_branch(Instruction::with_data(Opcode::JZ, &[Opcode::JZ as u8, 1, 0x40])).unwrap();
+    assert_eq!(vm.pc(), 0x40);
+    vm.jump(0);
+    vm.execute_branch(Instruction::with_data(Opcode::JNZ, &[Opcode::JNZ as u8, 1, 0x40])).unwrap();
+    assert_ne!(vm.pc(), 0x40);
+}
+
+#[test]
+fn test_branch() {
+    let mut vm = VM::new();
+
+    vm.mem.write_bytes(0,
+        // MOV R01 0x0A
+        &[Opcode::MOV_REG_IMM as u8, 0b0001_0000, 1, 0x0A]
+    );
+    vm.mem.write_bytes(1,
+        // ADD R02 R02 R01
+        &[Opcode::ADD as u8, 0b00_000000, 2, 2, 1]
+    );
+    vm.mem.write_bytes(2,
+        // SUB R01 R01 0x01
+        &[Opcode::SUB as u8, 0b01_000001, 1, 1, 1]
+    );
+    vm.mem.write_bytes(3,
+        // JNZ -19, back to byte 0x08
+        &[Opcode::JNZ as u8, 0x81, 0xED]
+    );
+    vm.mem.write_bytes(4,
+        // CMPILT R02 0x37, both passing and skipping continue at word 6
+        &[Opcode::CMP_ILT_REG_IMM as u8, 0b0001_0000, 2, 0x37]
+    );
+    vm.mem.write_bytes(5,
+        // JGE [0x40]
+        &[Opcode::JGE as u8, 1, 0x40]
+    );
+    vm.mem.write_bytes(6,
+        // JZ [0x48]
+        &[Opcode::JZ as u8, 1, 0x48]
+    );
+    vm.mem.write_bytes(7,
+        // MOV R00 0x01
+        &[Opcode::MOV_REG_IMM as u8, 0b0001_0000, 0, 1]
+    );
+    vm.mem.write_bytes(8,
+        // CAL HLT
+        &[Opcode::CAL as u8, 0x9D]
+    );
+    vm.mem.write_bytes(9,
+        // MOV R00 0x02, then CAL HLT
+        &[Opcode::MOV_REG_IMM as u8, 0b0001_0000, 0, 2, Opcode::CAL as u8, 0x9D]
+    );
+
+    // 10 + 9 + ... + 1 = 55, which is not less than 55 so CMPILT skips
+    // the JGE, then the equal comparison takes JZ
+    assert_eq!(vm.run().unwrap(), ExitReason::Halted(2));
+    assert_eq!(vm.reg.get(&2), 55);
+}
+
+#[test]
+fn test_pc() {
+    let mut vm = VM::new();
+
+    // Packed instructions, several of which straddle word boundaries
+    vm.mem.write_bytes(0, &[
+        Opcode::MOV_REG_IMM as u8, 0b0001_0000, 1, 5, // MOV R01 0x05
+        Opcode::JSR as u8, 1, 0x40, // JSR [0x40]
+        Opcode::CMP_EQ_REG_IMM as u8, 0b0001_0000, 1, 6, // CMPEQ R01 0x06
+        Opcode::MOV_REG_IMM as u8, 0b0001_0000, 0, 1, // MOV R00 0x01
+        Opcode::CAL as u8, 0x9D // CAL HLT
+    ]);
+    vm.mem.write_bytes(8, &[
+        Opcode::MOV_REG_IMM as u8, 0b0001_0000, 2, 9, // MOV R02 0x09
+        Opcode::JMP_REG as u8, 0xFF // RET (JMP R255)
+    ]);
+
+    assert_eq!(vm.run().unwrap(), ExitReason::Halted(0));
+    assert_eq!(vm.reg.get(&255), 7);
+    assert_eq!(vm.reg.get(&2), 9);
+    assert_eq!(vm.pc(), 17);
+}
+
+#[test]
+fn test_file_load() {
+    let tmp = std::env::temp_dir().join(format!("brandon-file-load-{}", std::process::id()));
+    let dir = tmp.join("modules");
+
+    std::fs::create_dir_all(&dir).unwrap();
+    // MOV R02 0x2A, MOV R03 0x2B, RET
+    std::fs::write(dir.join("lib.bin"), [
+        Opcode::MOV_REG_IMM as u8, 0b0001_0000, 2, 0x2A,
+        Opcode::MOV_REG_IMM as u8, 0b0001_0000, 3, 0x2B,
+        Opcode::RET as u8
+    ]).unwrap();
+    std::fs::write(dir.join("bad.bin"), b"BRDN").unwrap();
+
+    let mut split = Container::new(0);
+    split.sections.push(container::Section {
+        kind: SectionKind::Code,
+        load: 0,
+        bytes: vec![Opcode::RET as u8]
+    });
+    split.sections.push(container::Section {
+        kind: SectionKind::Data,
+        load: 1,
+        bytes: vec![0x29]
+    });
+    std::fs::write(dir.join("split.bin"), split.to_bytes().unwrap()).unwrap();
+    std::fs::write(tmp.join("outside.bin"), [Opcode::RET as u8]).unwrap();
+
+    let mut vm = VM::new();
+
+    // Without a module directory nothing can be loaded
+    assert_eq!(vm.load_module("lib.bin", 0x200), Err(LOAD_DENIED));
+
+    vm.modules = Some(dir.clone());
+
+    assert_eq!(vm.load_module("../outside.bin", 0x200), Err(LOAD_DENIED));
+    assert_eq!(vm.load_module("missing.bin", 0x200), Err(LOAD_UNREADABLE));
+    assert_eq!(vm.load_module("./sub/../missing.bin", 0x200), Err(LOAD_UNREADABLE));
+    // Missing files outside the directory look the same as existing ones
+    assert_eq!(vm.load_module("../missing.bin", 0x200), Err(LOAD_DENIED));
+    assert_eq!(vm.load_module("sub/../../outside.bin", 0x200), Err(LOAD_DENIED));
+    assert_eq!(vm.load_module("/missing.bin", 0x200), Err(LOAD_DENIED));
+    assert_eq!(vm.load_module("bad.bin", 0x200), Err(LOAD_INVALID));
+    // Two words do not fit at the last address
+    assert_eq!(vm.load_module("lib.bin", 0xFFFF_FFFF), Err(LOAD_INVALID));
+    // Two words still need a whole page of the limit
+    vm.mem.set_limit(Some(0xFFF));
+    assert_eq!(vm.load_module("lib.bin", 0x200), Err(LOAD_INVALID));
+    // Sections sharing a page are charged for it once
+    vm.mem.set_limit(Some(0x1000));
+    assert_eq!(vm.load_module("split.bin", 0x400), Ok(()));
+    vm.mem.set_limit(None);
+
+    vm.mem.write_utf16(0x100, "lib.bin".to_owned());
+    vm.mem.write_bytes(0, &[
+        Opcode::FILE_LOAD as u8, 0b0010_0010, 1, 0x01, 0x00, 0x02, 0x00, // FLX R01 [0x100] [0x200]
+        Opcode::CALL as u8, 2, 0x10, 0x00, // CALL [0x1000]
+        Opcode::CAL as u8, 0x9D // CAL HLT
+    ]);
+
+    assert_eq!(vm.run().unwrap(), ExitReason::Halted(0));
+    assert_eq!(vm.reg.get(&1), LOAD_OK);
+    assert_eq!(vm.reg.get(&2), 0x2A);
+
+    // Modules cannot be loaded over sections already loaded
+    assert_eq!(vm.load_module("lib.bin", 0x201), Err(LOAD_DENIED));
+    assert_eq!(vm.load_module("lib.bin", 0x1FF), Err(LOAD_DENIED));
+    assert_eq!(vm.load_module("lib.bin", 0x202), Ok(()));
+
+    std::fs::remove_dir_all(&tmp).unwrap();
+}
+
+#[test]
+fn test_input() {
+    let mut vm = VM::with_io(Box::new(io::Buffer::new("é\nhello\n-42\nnope\n")));
+
+    // GETC
+    vm.execute_call(Instruction::with_data(Opcode::CAL, &[Opcode::CAL as u8, 0x90])).unwrap();
+    assert_eq!(vm.reg.get(&0), 'é' as u64);
+
+    // GETL, the rest of the first line is empty
+    vm.reg.set(0, 0x10);
+    vm.execute_call(Instruction::with_data(Opcode::CAL, &[Opcode::CAL as u8, 0x91])).unwrap();
+    assert_eq!(vm.reg.get(&0), 0);
+
+    vm.reg.set(0, 0x10);
+    vm.execute_call(Instruction::with_data(Opcode::CAL, &[Opcode::CAL as u8, 0x91])).unwrap();
+    assert_eq!(vm.reg.get(&0), 5);
+    assert_eq!(vm.mem.read_utf16(0x10), "hello");
+
+    // GETI
+    vm.execute_call(Instruction::with_data(Opcode::CAL, &[Opcode::CAL as u8, 0x92])).unwrap();
+    assert_eq!(vm.reg.get(&0) as i64, -42);
+    assert_eq!(vm.flags.get() & FLAG_CARRY, 0);
+
+    // Invalid numbers and the end of input set the carry flag
+    vm.execute_call(Instruction::with_data(Opcode::CAL, &[Opcode::CAL as u8, 0x92])).unwrap();
+    assert_eq!((vm.reg.get(&0), vm.flags.get() & FLAG_CARRY), (0, FLAG_CARRY));
+
+    vm.execute_call(Instruction::with_data(Opcode::CAL, &[Opcode::CAL as u8, 0x90])).unwrap();
+    assert_eq!((vm.reg.get(&0), vm.flags.get() & FLAG_CARRY), (0, FLAG_CARRY));
+}
+
+#[test]
+fn test_load_store() {
+    let vm = VM::new();
+
+    vm.mem.write(0x10, 0x0011_2233_4455_6677);
+    vm.mem.write(0x11, 0x8899_AABB_CCDD_EEFF);
+
+    // Loads zero extend, and may span two words
+    vm.reg.set(1, 0x80);
+    vm.execute_load_store(Instruction::with_data(Opcode::LOADB, &[Opcode::LOADB as u8, 0b00_000000, 0, 1])).unwrap();
+    assert_eq!(vm.reg.get(&0), 0x00);
+
+    vm.execute_load_store(Instruction::with_data(Opcode::LOADH, &[Opcode::LOADH as u8, 0b01_000001, 0, 0x87])).unwrap();
+    assert_eq!(vm.reg.get(&0), 0x7788);
+
+    vm.execute_load_store(Instruction::with_data(Opcode::LOADW, &[Opcode::LOADW as u8, 0b01_000001, 0, 0x84])).unwrap();
+    assert_eq!(vm.reg.get(&0), 0x4455_6677_8899_AABB);
+
+    // Stores keep the rest of the words
+    vm.reg.set(2, 0x1234_5678_9ABC_DEF0);
+    vm.execute_load_store(Instruction::with_data(Opcode::STOREB, &[Opcode::STOREB as u8, 0b01_000001, 2, 0x81])).unwrap();
+    assert_eq!(vm.mem.read(0x10), Some(0x00F0_2233_4455_6677));
+
+    vm.execute_load_store(Instruction::with_data(Opcode::STOREH, &[Opcode::STOREH as u8, 0b01_000001, 2, 0x87])).unwrap();
+    assert_eq!(vm.mem.read(0x10), Some(0x00F0_2233_4455_66DE));
+    assert_eq!(vm.mem.read(0x11), Some(0xF099_AABB_CCDD_EEFF));
+
+    // Stores fill in empty words, loads fault on them
+    vm.execute_load_store(Instruction::with_data(Opcode::STOREW, &[Opcode::STOREW as u8, 0b01_000001, 2, 0x94])).unwrap();
+    assert_eq!(vm.mem.read(0x12), Some(0x0000_0000_1234_5678));
+    assert_eq!(vm.mem.read(0x13), Some(0x9ABC_DEF0_0000_0000));
+    assert_eq!(vm.execute_load_store(Instruction::with_data(Opcode::LOADB, &[Opcode::LOADB as u8, 0b01_000001, 0, 0xA0])), Err(Fault::InvalidAddress(0x14)));
+
+    // Nothing is written if either word is protected
+    vm.mem.protect(0x11, 1, READ);
+    assert_eq!(vm.execute_load_store(Instruction::with_data(Opcode::STOREW, &[Opcode::STOREW as u8, 0b01_000001, 2, 0x84])), Err(Fault::NotWritable(0x11)));
+    assert_eq!(vm.mem.read(0x10), Some(0x00F0_2233_4455_66DE));
+
+    assert_eq!(vm.execute_load_store(Instruction::with_data(Opcode::LOADB, &[Opcode::LOADB as u8, 0b10_000000, 0, 1])), Err(Fault::InvalidOption(0b10_000000)));
+}
+
+#[test]
+fn test_memory_limit() {
+    let mut vm = VM::with_io(Box::new(io::Buffer::new("hello world\n")));
+    vm.mem = Memory::with_backend(Box::new(memory::Sparse::default()));
+
+    // MOV [0x10] 1, MOV [0x11] 2, then pushing R00 needs a third word
+    vm.mem.write_bytes(0, &[
+        Opcode::MOV_MEM_IMM as u8, 0b0001_0001, 0x10, 0x01,
+        Opcode::MOV_MEM_IMM as u8, 0b0001_0001, 0x11, 0x02,
+        Opcode::PUSH as u8, 0b00_000000, 0x00
+    ]);
+    vm.mem.set_limit(Some(vm.mem.usage() + 2));
+
+    assert_eq!(vm.run(), Err(VmError::new(1, Some(Opcode::PUSH), Fault::QuotaExceeded(STACK_END - 1))));
+    assert_eq!(vm.mem.read(0x11), Some(2));
+
+    // GETL writes nothing if the line does not fit
+    vm.reg.set(0, 0x20);
+    assert_eq!(vm.execute_call(Instruction::with_data(Opcode::CAL, &[Opcode::CAL as u8, 0x91])), Err(Fault::QuotaExceeded(0x20)));
+    assert_eq!(vm.mem.read(0x20), None);
+}
+
+#[test]
+fn test_host_calls() {
+    use std::rc::Rc;
+    use std::cell::RefCell;
+
+    let mut vm = VM::new();
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let sink = log.clone();
+
+    vm.register(0x10, "LOG", move |reg, mem| {
+        sink.borrow_mut().push(mem.read_utf16(reg.get(&0) as u32));
+        Ok(())
+    }).unwrap();
+    vm.register(0x11, "clock", |reg, _| {
+        reg.set(0, 1_700_000_000);
+        Ok(())
+    }).unwrap();
+    vm.register(0x12, "config", |reg, _| Err(Fault::InvalidAddress(reg.get(&1) as u32))).unwrap();
+
+    assert!(vm.calls().contains(&("log".to_owned(), 0x10)));
+    assert!(vm.calls().contains(&("hlt".to_owned(), 0x9D)));
+
+    vm.mem.write_utf16(0x40, "hi".to_owned());
+    vm.mem.write_bytes(0, &[
+        Opcode::MOV_REG_IMM as u8, 0b0001_0000, 0, 0x40, // MOV R00 0x40
+        Opcode::CAL as u8, 0x10, // CAL LOG
+        Opcode::CAL as u8, 0x11, // CAL CLOCK
+        Opcode::CAL as u8, 0x12 // CAL CONFIG
+    ]);
+
+    let err = vm.run().unwrap_err();
+    assert_eq!((err.opcode, err.cause), (Some(Opcode::CAL), Fault::InvalidAddress(0)));
+    assert_eq!(*log.borrow(), vec!["hi".to_owned()]);
+    assert_eq!(vm.reg.get(&0), 1_700_000_000);
+
+    // Names move with the call when registered again
+    vm.register(0x13, "log", |_, _| Ok(())).unwrap();
+    assert!(!vm.calls().contains(&("log".to_owned(), 0x10)));
+
+    // Built in calls and codes held by another name are refused
+    assert_eq!(vm.register(0x9D, "stop", |_, _| Ok(())), Err(CallError::BuiltIn("stop".to_owned(), 0x9D)));
+    assert_eq!(vm.register(0x20, "HLT", |_, _| Ok(())), Err(CallError::BuiltIn("hlt".to_owned(), 0x20)));
+    assert_eq!(vm.register(0x11, "time", |_, _| Ok(())), Err(CallError::Taken("clock".to_owned(), 0x11)));
+    assert!(vm.calls().contains(&("clock".to_owned(), 0x11)));
+}
+
+#[test]
+fn test_protection() {
+    use container::Section;
+
+    let mut container = Container::new(0);
+
+    container.sections.push(Section {
+        kind: SectionKind::Code,
+        load: 0,
+        bytes: vec![
+            Opcode::MOV_MEM_IMM as u8, 0b0001_0001, 0x10, 0x29, // MOV [0x10] 0x29
+            Opcode::MOV_REG_MEM as u8, 0, 0, 0, 0, 0x10, // MOV R00 [0x10]
+            Opcode::MOV_MEM_IMM as u8, 0b0001_0001, 0x01, 0x29, // MOV [0x01] 0x29
+            Opcode::CAL as u8, 0x9D // CAL HLT
+        ]
+    });
+    container.sections.push(Section {
+        kind: SectionKind::Data,
+        load: 0x10,
+        // CAL HLT, which must not be executed
+        bytes: vec![Opcode::CAL as u8, 0x9D]
+    });
+
+    let mut vm = VM::new();
+    vm.load(&container).unwrap();
+
+    // Data is writable, code is not
+    assert_eq!(vm.run(), Err(VmError::new(1, Some(Opcode::MOV_MEM_IMM), Fault::NotWritable(1))));
+    assert_eq!(vm.reg.get(&0), 0x29);
+
+    // Data is not executable
+    let mut vm = VM::new();
+    vm.load(&container).unwrap();
+    vm.mem.write_bytes(0, &[Opcode::JMP_IMM as u8, 1, 0x80]);
+
+    assert_eq!(vm.run(), Err(VmError::new(0x10, None, Fault::NotExecutable(0x10))));
+
+    // Padding in data is not skipped into the code after it
+    container.sections.insert(1, Section {
+        kind: SectionKind::Data,
+        load: 3,
+        bytes: vec![0; 8]
+    });
+    container.sections.push(Section {
+        kind: SectionKind::Code,
+        load: 4,
+        bytes: vec![Opcode::CAL as u8, 0x9D]
+    });
+
+    let mut vm = VM::new();
+    vm.load(&container).unwrap();
+    vm.mem.write_bytes(0, &[Opcode::JMP_IMM as u8, 1, 0x18]);
+
+    assert_eq!(vm.run(), Err(VmError::new(3, None, Fault::NotExecutable(3))));
+
+    // Every word an instruction covers must be executable
+    let mut vm = VM::new();
+    vm.mem.write_bytes(0, &[
+        Opcode::ADD as u8, 0b10_001000, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 2, // ADD R01 1 2
+        Opcode::CAL as u8, 0x9D // CAL HLT
+    ]);
+    vm.mem.protect(1, 1, READ);
+
+    assert_eq!(vm.run(), Err(VmError::new(0, Some(Opcode::ADD), Fault::NotExecutable(1))));
+
+    // And every word of a string must be readable, up to its terminator
+    vm.mem.write_utf16(0x20, "hello, world".to_owned());
+    vm.mem.protect(0x22, 2, WRITE);
+    vm.reg.set(0, 0x20);
+
+    let pnt = Instruction::with_data(Opcode::CAL, &[Opcode::CAL as u8, 0x9A]);
+    assert_eq!(vm.execute_call(pnt), Err(Fault::NotReadable(0x22)));
+
+    // A string filling its last word ends at the empty word after it
+    vm.mem.write_utf16(0x30, "hello, w".to_owned());
+    vm.mem.protect(0x32, 1, WRITE);
+    vm.reg.set(0, 0x30);
+    assert_eq!(vm.execute_call(pnt), Err(Fault::NotReadable(0x32)));
+
+    vm.mem.write_utf16(0x30, "hello\0".to_owned());
+    assert_eq!(vm.execute_call(pnt), Ok(()));
+}
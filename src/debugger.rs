@@ -0,0 +1,285 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, BufRead, Write};
+use brandon::bvm::{VM, Step};
+use brandon::basm::disassembler::render;
+
+const HELP: &str = "commands:
+    s, step [n]             execute n instructions (default 1)
+    c, continue             run until a breakpoint, watchpoint or halt
+    b, break <addr>         break before executing the word at addr
+    d, delete <addr>        remove a breakpoint
+    w, watch <addr>         stop when the memory at addr changes
+    u, unwatch <addr>       remove a watchpoint
+    r, regs                 print registers which have been set
+    m, mem <addr> [len]     print len words of memory (default 1)
+    i, inst                 print the next instruction
+    h, help                 print this message
+    q, quit                 exit the debugger";
+
+pub struct Debugger<R, W> {
+    vm: VM,
+    input: R,
+    output: W,
+    breakpoints: BTreeSet<u32>,
+    // Watched addresses with the last value seen there
+    watchpoints: BTreeMap<u32, Option<u64>>,
+    halted: bool
+}
+
+enum Stop {
+    // Keep running
+    None,
+    // A breakpoint or watchpoint was hit, or the program halted
+    Paused,
+    // The VM faulted
+    Fault
+}
+
+impl<R: BufRead, W: Write> Debugger<R, W> {
+    pub fn new(vm: VM, input: R, output: W) -> Debugger<R, W> {
+        Debugger {
+            vm,
+            input,
+            output,
+            breakpoints: BTreeSet::new(),
+            watchpoints: BTreeMap::new(),
+            halted: false
+        }
+    }
+
+    pub fn run(&mut self) -> io::Result<()> {
+        // Read and execute commands until quit or end of input
+        self.print_inst()?;
+
+        loop {
+            write!(self.output, "(bdb) ")?;
+            self.output.flush()?;
+
+            let mut line = String::new();
+
+            if self.input.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+
+            let args: Vec<&str> = line.split_whitespace().collect();
+
+            match args.as_slice() {
+                [] => {},
+                ["s"] | ["step"] => self.step(1)?,
+                ["s", n] | ["step", n] => match parse_number(n) {
+                    Some(n) => self.step(n)?,
+                    None => writeln!(self.output, "invalid count {}", n)?
+                },
+                ["c"] | ["continue"] => self.cont()?,
+                ["b", addr] | ["break", addr] => match parse_number(addr) {
+                    Some(addr) => {
+                        self.breakpoints.insert(addr as u32);
+                        writeln!(self.output, "breakpoint at {:#010X}", addr)?;
+                    },
+                    None => writeln!(self.output, "invalid address {}", addr)?
+                },
+                ["d", addr] | ["delete", addr] => match parse_number(addr) {
+                    Some(addr) if self.breakpoints.remove(&(addr as u32)) => {},
+                    _ => writeln!(self.output, "no breakpoint at {}", addr)?
+                },
+                ["w", addr] | ["watch", addr] => match parse_number(addr) {
+                    Some(addr) => {
+                        let addr = addr as u32;
+                        self.watchpoints.insert(addr, self.vm.mem.read(addr));
+                        writeln!(self.output, "watching {:#010X}", addr)?;
+                    },
+                    None => writeln!(self.output, "invalid address {}", addr)?
+                },
+                ["u", addr] | ["unwatch", addr] => match parse_number(addr) {
+                    Some(addr) if self.watchpoints.remove(&(addr as u32)).is_some() => {},
+                    _ => writeln!(self.output, "no watchpoint at {}", addr)?
+                },
+                ["r"] | ["regs"] => self.print_regs()?,
+                ["m", addr] | ["mem", addr] => match parse_number(addr) {
+                    Some(addr) => self.print_mem(addr as u32, 1)?,
+                    None => writeln!(self.output, "invalid address {}", addr)?
+                },
+                ["m", addr, len] | ["mem", addr, len] => match (parse_number(addr), parse_number(len)) {
+                    (Some(addr), Some(len)) => self.print_mem(addr as u32, len as u32)?,
+                    _ => writeln!(self.output, "invalid range {} {}", addr, len)?
+                },
+                ["i"] | ["inst"] => self.print_inst()?,
+                ["h"] | ["help"] => writeln!(self.output, "{}", HELP)?,
+                ["q"] | ["quit"] => return Ok(()),
+                _ => writeln!(self.output, "unknown command, try help")?
+            }
+        }
+    }
+
+    fn step(&mut self, n: u64) -> io::Result<()> {
+        // Single step n instructions, stopping early on watchpoints
+        for _ in 0..n {
+            match self.step_once()? {
+                Stop::None => {},
+                Stop::Paused | Stop::Fault => return Ok(())
+            }
+        }
+
+        self.print_inst()
+    }
+
+    fn cont(&mut self) -> io::Result<()> {
+        // Run until something stops execution. Always executes at least
+        // one instruction, so continuing from a breakpoint moves on.
+        let mut last = self.vm.addr;
+
+        loop {
+            match self.step_once()? {
+                Stop::None => {},
+                Stop::Paused | Stop::Fault => return Ok(())
+            }
+
+            if let Ok(decoded) = self.vm.fetch() {
+                // Only break when entering the word, not on every
+                // instruction packed inside it
+                if decoded.addr != last && self.breakpoints.contains(&decoded.addr) {
+                    writeln!(self.output, "breakpoint at {:#010X}", decoded.addr)?;
+                    return self.print_inst();
+                }
+
+                last = decoded.addr;
+            }
+        }
+    }
+
+    fn step_once(&mut self) -> io::Result<Stop> {
+        if self.halted {
+            writeln!(self.output, "the program has halted")?;
+            return Ok(Stop::Paused);
+        }
+
+        match self.vm.step() {
+            Ok(Step::Executed(_)) => {},
+            Ok(Step::OutOfFuel) => {
+                writeln!(self.output, "out of fuel")?;
+                return Ok(Stop::Paused);
+            },
+            Err(err) => {
+                writeln!(self.output, "{}", err)?;
+                return Ok(Stop::Fault);
+            }
+        }
+
+        if !self.vm.running {
+            self.halted = true;
+            writeln!(self.output, "halted with exit code {}", self.vm.reg.get(&0))?;
+            return Ok(Stop::Paused);
+        }
+
+        let mut stop = Stop::None;
+
+        for (addr, last) in self.watchpoints.iter_mut() {
+            let data = self.vm.mem.read(*addr);
+
+            if data != *last {
+                writeln!(self.output, "watchpoint {:#010X}: {} -> {}", addr, format_word(*last), format_word(data))?;
+                *last = data;
+                stop = Stop::Paused;
+            }
+        }
+
+        if let Stop::Paused = stop {
+            self.print_inst()?;
+        }
+
+        Ok(stop)
+    }
+
+    fn print_inst(&mut self) -> io::Result<()> {
+        if self.halted {
+            return Ok(());
+        }
+
+        match self.vm.fetch() {
+            Ok(decoded) => writeln!(self.output, "{:#010X}+{}  {}", decoded.addr, decoded.offset, render(decoded.instruction())),
+            Err(err) => writeln!(self.output, "{}", err)
+        }
+    }
+
+    fn print_regs(&mut self) -> io::Result<()> {
+        for (register, data) in self.vm.reg.list() {
+            writeln!(self.output, "R{:02}  {:#018X}  {}", register, data, data)?;
+        }
+
+        Ok(())
+    }
+
+    fn print_mem(&mut self, start: u32, len: u32) -> io::Result<()> {
+        for addr in (start..).take(len as usize) {
+            writeln!(self.output, "{:#010X}  {}", addr, format_word(self.vm.mem.read(addr)))?;
+        }
+
+        Ok(())
+    }
+}
+
+fn format_word(word: Option<u64>) -> String {
+    match word {
+        Some(word) => format!("{:#018X}", word),
+        None => "empty".to_owned()
+    }
+}
+
+fn parse_number(string: &str) -> Option<u64> {
+    // Parses decimal or 0x prefixed hex, addresses may be in brackets
+    let string = string.trim_start_matches('[').trim_end_matches(']');
+
+    match string.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => string.parse().ok()
+    }
+}
+
+#[test]
+fn test_debugger() {
+    use brandon::bvm::instructions::Opcode;
+
+    let vm = VM::new();
+
+    vm.mem.write_bytes(0,
+        // MOV R01 0x29
+        &[Opcode::MOV_REG_IMM as u8, 0b0001_0000, 1, 0x29]
+    );
+    vm.mem.write_bytes(1,
+        // MOV [0x10] R01
+        &[Opcode::MOV_MEM_REG as u8, 0, 0, 0, 0x10, 1]
+    );
+    vm.mem.write_bytes(2,
+        // MOV R02 0x02
+        &[Opcode::MOV_REG_IMM as u8, 0b0001_0000, 2, 2]
+    );
+    vm.mem.write_bytes(3,
+        // CAL HLT
+        &[Opcode::CAL as u8, 0x9D]
+    );
+
+    let input = "b 2\nw [0x10]\nc\nm 0x10\nc\nr\ns\nq\n";
+    let mut output: Vec<u8> = Vec::new();
+    let mut debugger = Debugger::new(vm, input.as_bytes(), &mut output);
+
+    debugger.run().unwrap();
+
+    assert_eq!(debugger.vm.reg.get(&2), 2);
+
+    let output = String::from_utf8(output).unwrap();
+    let expected = "\
+0x00000000+0  MOV R01 0x29
+(bdb) breakpoint at 0x00000002
+(bdb) watching 0x00000010
+(bdb) watchpoint 0x00000010: empty -> 0x0000000000000029
+0x00000002+0  MOV R02 0x2
+(bdb) 0x00000010  0x0000000000000029
+(bdb) halted with exit code 0
+(bdb) R01  0x0000000000000029  41
+R02  0x0000000000000002  2
+R254  0x00000000FFFF0000  4294901760
+(bdb) the program has halted
+(bdb) ";
+
+    assert_eq!(output, expected);
+}
@@ -0,0 +1,11 @@
+extern crate num;
+#[macro_use] extern crate num_derive;
+
+#[path = "bvm/vm.rs"]
+pub mod bvm;
+
+pub mod basm {
+    pub mod tokenizer;
+    pub mod assembler;
+    pub mod disassembler;
+}
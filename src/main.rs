@@ -1,9 +1,199 @@
-extern crate num;
-#[macro_use] extern crate num_derive;
+mod debugger;
 
-#[path = "bvm/vm.rs"]
-pub mod bvm;
+use std::env;
+use std::fs;
+use std::io;
+use std::process;
+use std::path::{Path, PathBuf};
+use brandon::bvm::{VM, ExitReason};
+use brandon::bvm::externals;
+use brandon::bvm::io::Unbuffered;
+use brandon::bvm::container::Container;
+use brandon::basm::tokenizer::Tokenizer;
+use brandon::basm::assembler::Assembler;
+use brandon::basm::disassembler::disassemble;
+use debugger::Debugger;
+
+const USAGE: &str = "usage: brandon <command> [args]
+
+commands:
+    run <file>              execute a bytecode file
+    asm <src> -o <out>      assemble a basm source file
+    disasm <file>           print a bytecode file as basm source
+    debug <file>            step through a bytecode file interactively";
+
+// Exit codes reported to the host shell. The CLI's own codes sit above
+// every code a guest program can exit with.
+const EXIT_OK: i32 = 0;
+const EXIT_GUEST_MAX: i32 = 124;
+const EXIT_FAULT: i32 = 125;
+const EXIT_USAGE: i32 = 126;
+const EXIT_IO: i32 = 127;
 
 fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let code = match args.first().map(|s| s.as_str()) {
+        Some("run") if args.len() == 2 => run(&args[1]),
+        Some("asm") if args.len() == 4 && args[2] == "-o" => asm(&args[1], &args[3]),
+        Some("disasm") if args.len() == 2 => disasm(&args[1]),
+        Some("debug") if args.len() == 2 => debug(&args[1]),
+        _ => {
+            eprintln!("{}", USAGE);
+            EXIT_USAGE
+        }
+    };
+
+    process::exit(code);
+}
+
+fn load(path: &str) -> Option<Vec<u8>> {
+    // Read a file, reporting failures to stderr
+    match externals::read(path) {
+        Ok(bytes) => Some(bytes),
+        Err(err) => {
+            eprintln!("brandon: cannot open {}: {}", path, err);
+            None
+        }
+    }
+}
+
+fn program(path: &str) -> Option<Container> {
+    // Read a bytecode file. Files without the container header are raw
+    // code loaded at address 0.
+    let bytes = load(path)?;
+
+    match Container::read(&bytes) {
+        Ok(container) => Some(container),
+        Err(err) => {
+            eprintln!("brandon: cannot load {}: {}", path, err);
+            None
+        }
+    }
+}
+
+fn machine() -> VM {
+    // The VM programs run on. Host calls registered here can be called
+    // by name in programs assembled with asm.
+    VM::new()
+}
+
+fn vm(path: &str) -> Option<VM> {
+    // Create a VM with the program at path loaded
+    let container = program(path)?;
+    let mut vm = machine();
+
+    // FILE_LOAD may load modules next to the program
+    vm.modules = match Path::new(path).parent() {
+        Some(dir) if dir != Path::new("") => Some(dir.to_path_buf()),
+        _ => Some(PathBuf::from("."))
+    };
+
+    match vm.load(&container) {
+        Ok(_) => Some(vm),
+        Err(err) => {
+            eprintln!("brandon: cannot load {}: {}", path, err);
+            None
+        }
+    }
+}
+
+fn run(path: &str) -> i32 {
+    // Load a program and execute it from its entry point
+    let mut vm = match vm(path) {
+        Some(vm) => vm,
+        None => return EXIT_IO
+    };
+
+    match vm.run() {
+        Ok(ExitReason::Halted(code)) => exit_code(code),
+        Ok(_) => EXIT_OK,
+        Err(err) => {
+            eprintln!("brandon: {}", err);
+            EXIT_FAULT
+        }
+    }
+}
+
+fn exit_code(code: u64) -> i32 {
+    // Pass a guest's exit code through, clamped below the CLI's own
+    // codes so a program cannot pass for a fault or a failed load
+    code.min(EXIT_GUEST_MAX as u64) as i32
+}
+
+fn asm(src: &str, out: &str) -> i32 {
+    // Assemble a basm source file into bytecode
+    let source = match load(src).map(String::from_utf8) {
+        Some(Ok(source)) => source,
+        Some(Err(_)) => {
+            eprintln!("brandon: {} is not valid UTF-8", src);
+            return EXIT_IO;
+        },
+        None => return EXIT_IO
+    };
+
+    let tokens = Tokenizer::load(&source).tokenize();
+
+    let container = match Assembler::for_vm(&tokens, &machine()).container() {
+        Ok(container) => container,
+        Err(err) => {
+            eprintln!("brandon: {}: {}", src, err);
+            return EXIT_FAULT;
+        }
+    };
+
+    let bytes = match container.to_bytes() {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            eprintln!("brandon: {}: {}", src, err);
+            return EXIT_FAULT;
+        }
+    };
+
+    if let Err(err) = fs::write(out, bytes) {
+        eprintln!("brandon: cannot write {}: {}", out, err);
+        return EXIT_IO;
+    }
+
+    EXIT_OK
+}
+
+fn disasm(path: &str) -> i32 {
+    // Print a bytecode file as basm source
+    let container = match program(path) {
+        Some(container) => container,
+        None => return EXIT_IO
+    };
+
+    print!("{}", disassemble(&container));
+    EXIT_OK
+}
+
+fn debug(path: &str) -> i32 {
+    // Load a program and start the debugger at its entry point
+    let vm = match vm(path) {
+        Some(vm) => vm,
+        None => return EXIT_IO
+    };
+
+    // Commands and the program's input share stdin
+    let mut debugger = Debugger::new(vm, Unbuffered::new(io::stdin()), io::stdout());
+
+    match debugger.run() {
+        Ok(_) => EXIT_OK,
+        Err(err) => {
+            eprintln!("brandon: {}", err);
+            EXIT_IO
+        }
+    }
+}
 
-}
\ No newline at end of file
+#[test]
+fn test_exit_code() {
+    assert_eq!(exit_code(0), EXIT_OK);
+    assert_eq!(exit_code(1), 1);
+    assert_eq!(exit_code(124), 124);
+    assert_eq!(exit_code(125), EXIT_GUEST_MAX);
+    assert_eq!(exit_code(256), EXIT_GUEST_MAX);
+    assert_eq!(exit_code(u64::MAX), EXIT_GUEST_MAX);
+}